@@ -0,0 +1,160 @@
+use hashbrown::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Mutex;
+
+use crate::Config;
+
+pub static DUPLICATE_POST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+const SHARDS: usize = 64;
+
+/// the text hashes of every post counted so far, sharded so that workers rarely wait on
+/// each other
+static SEEN: [Mutex<Option<HashSet<u128>>>; SHARDS] = [const { Mutex::new(None) }; SHARDS];
+
+/// ends each text, so that `["a", "b"]` and `["ab"]` differ, 0xff never occurs in utf-8
+const END: u8 = 0xff;
+
+/// 128 bits, so that distinct posts practically never collide even in huge corpora, whitespace
+/// around a text doesn't change its words so it is ignored
+fn hash<'a>(texts: impl IntoIterator<Item = &'a str>) -> u128 {
+    let mut halves = halves();
+
+    for text in texts {
+        for half in &mut halves {
+            half.write(text.trim().as_bytes());
+            half.write_u8(END);
+        }
+    }
+
+    finish(halves)
+}
+
+fn halves() -> [DefaultHasher; 2] {
+    [0_u8, 1].map(|seed| {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        hasher
+    })
+}
+
+fn finish(halves: [DefaultHasher; 2]) -> u128 {
+    (u128::from(halves[0].finish()) << 64) | u128::from(halves[1].finish())
+}
+
+/// the hash of a single text that is read a piece at a time, the same as if it was read at once
+pub struct Incremental {
+    halves: [DefaultHasher; 2],
+    started: bool,
+    /// whitespace that is only hashed if more text follows it
+    pending: String,
+}
+
+impl Incremental {
+    pub fn new() -> Self {
+        Incremental {
+            halves: halves(),
+            started: false,
+            pending: String::new(),
+        }
+    }
+
+    pub fn write(&mut self, piece: &str) {
+        let piece = if self.started {
+            piece
+        } else {
+            piece.trim_start()
+        };
+
+        if piece.is_empty() {
+            return;
+        }
+
+        self.started = true;
+
+        let text = piece.trim_end();
+
+        if !text.is_empty() {
+            for half in &mut self.halves {
+                half.write(self.pending.as_bytes());
+                half.write(text.as_bytes());
+            }
+
+            self.pending.clear();
+        }
+
+        self.pending.push_str(&piece[text.len()..]);
+    }
+
+    /// like `is_duplicate` for the text written so far
+    pub fn is_duplicate(&self, config: &Config) -> bool {
+        config.dedup && insert(self.hash())
+    }
+
+    fn hash(&self) -> u128 {
+        let mut halves = self.halves.clone();
+
+        for half in &mut halves {
+            half.write_u8(END);
+        }
+
+        finish(halves)
+    }
+}
+
+/// if a post with exactly these text fields was already counted, always false without --dedup
+pub fn is_duplicate<'a>(config: &Config, texts: impl IntoIterator<Item = &'a str>) -> bool {
+    config.dedup && insert(hash(texts))
+}
+
+/// if the hash was already seen, and remembers it otherwise
+fn insert(hash: u128) -> bool {
+    let mut seen = SEEN[hash as usize % SHARDS]
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let is_new = seen.get_or_insert_with(HashSet::new).insert(hash);
+
+    if !is_new {
+        DUPLICATE_POST_COUNT.fetch_add(1, Relaxed);
+    }
+
+    !is_new
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incremental(pieces: &[&str]) -> u128 {
+        let mut hash = Incremental::new();
+
+        for piece in pieces {
+            hash.write(piece);
+        }
+
+        hash.hash()
+    }
+
+    #[test]
+    fn incremental_is_the_same_as_at_once() {
+        let text = "  \n first line\n\n  second line  \n \n";
+        let lines = text.split_inclusive('\n').collect::<Vec<_>>();
+
+        assert_eq!(incremental(&lines), hash(Some(text)));
+        assert_eq!(incremental(&[text]), hash(Some(text)));
+        assert_eq!(incremental(&["a b", " ", "c"]), hash(Some("a b c")));
+        assert_eq!(incremental(&[" ", "\n"]), hash(Some("")));
+    }
+
+    #[test]
+    fn whitespace_around_the_text_is_ignored() {
+        assert_eq!(hash(Some(" a b\n")), hash(Some("a b")));
+        assert_ne!(hash(Some("a  b")), hash(Some("a b")));
+        assert_ne!(hash(["a", "b"]), hash(["a b"]));
+        assert_ne!(hash(["a", "b"]), hash(["ab"]));
+        assert_ne!(hash(["a", ""]), hash(["a"]));
+    }
+}
@@ -0,0 +1,354 @@
+use hashbrown::HashMap;
+use log::warn;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::dedup;
+use crate::failure::Failure;
+use crate::input::Format;
+use crate::post::{Meta, Post};
+use crate::proc_file::{count_phrases, Seen};
+use crate::time;
+use crate::users::User;
+
+/// a column of a csv file, either by its header name or by its zero based index
+#[derive(Debug, Clone)]
+pub enum Column {
+    Name(String),
+    Index(usize),
+}
+
+impl FromStr for Column {
+    type Err = std::convert::Infallible;
+
+    fn from_str(column: &str) -> Result<Self, Self::Err> {
+        Ok(match column.parse() {
+            Ok(index) => Column::Index(index),
+            Err(_) => Column::Name(column.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Column::Name(name) => write!(f, "`{}`", name),
+            Column::Index(index) => write!(f, "#{}", index),
+        }
+    }
+}
+
+impl Column {
+    fn resolve(&self, headers: &csv::StringRecord) -> Option<usize> {
+        match self {
+            Column::Name(name) => headers.iter().position(|header| header == name),
+            Column::Index(index) => Some(*index).filter(|&index| index < headers.len()),
+        }
+    }
+}
+
+/// io errors keep their kind, so that invalid utf-8 of streamed files is reported as such
+fn read_failure(err: csv::Error) -> Failure {
+    if !err.is_io_error() {
+        return Failure::Read(io::Error::new(io::ErrorKind::InvalidData, err));
+    }
+
+    match err.into_kind() {
+        csv::ErrorKind::Io(err) => err.into(),
+        _ => unreachable!("checked to be an io error"),
+    }
+}
+
+pub fn process_delimited(
+    config: &crate::Config,
+    format: Format,
+    file_path: &Path,
+    file: impl Read,
+    phrase_counts: &mut crate::Map,
+) -> Result<(), Failure> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(if format == Format::Tsv { b'\t' } else { b',' })
+        .flexible(true)
+        .from_reader(file);
+
+    let headers = reader.headers().map_err(read_failure)?.clone();
+
+    let default_text_column = [Column::Name("text".to_owned())];
+    let text_columns = if config.text_column.is_empty() {
+        &default_text_column[..]
+    } else {
+        &config.text_column[..]
+    };
+
+    // none of the file is counted if a column is missing, so it fails as a whole
+    let resolve = |column: &Column| {
+        column
+            .resolve(&headers)
+            .ok_or_else(|| Failure::MissingColumn(column.to_string()))
+    };
+
+    let text_indices = text_columns
+        .iter()
+        .map(resolve)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // the user, timestamp and score columns are optional, but must exist if they are given
+    let user_index = config.user_column.as_ref().map(resolve).transpose()?;
+    let timestamp_index = config
+        .timestamp_column
+        .as_ref()
+        .map(resolve)
+        .transpose()?
+        .filter(|_| config.buckets.is_some() || config.since.is_some() || config.until.is_some());
+    let score_index = config
+        .score_column
+        .as_ref()
+        .map(resolve)
+        .transpose()?
+        .filter(|_| config.weight.is_some());
+
+    // --where only has a column to compare with if its selector is a single field
+    let predicate_indices = config
+        .predicates
+        .iter()
+        .map(|predicate| match predicate.column() {
+            Some(name) => resolve(&Column::Name(name.to_owned())),
+            None => Err(Failure::MissingColumn(
+                "for --where, filters of csv files must name a single column".to_owned(),
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // a file is the closest thing to a user scope that csv has, so a user's phrases are
+    // capped within it
+    if config.per_user_cap.is_some() && user_index.is_none() {
+        warn!(
+            "NO USER COLUMN {:?} --per-user-cap needs --user-column for csv files, counting without a cap",
+            file_path
+        );
+    }
+
+    let mut seen = HashMap::<User, Seen>::new();
+    let mut bad_rows = 0;
+    let mut record = csv::StringRecord::new();
+
+    loop {
+        match reader.read_record(&mut record) {
+            Ok(true) => (),
+            Ok(false) => break,
+            // the rest of the file can't be read
+            Err(err) if err.is_io_error() => return Err(read_failure(err)),
+            Err(err) => {
+                bad_rows += 1;
+
+                let line = err.position().map_or(0, csv::Position::line);
+                warn!("BAD LINE {:?}:{} {}", file_path, line, err);
+                continue;
+            }
+        }
+
+        let user = user_index
+            .and_then(|index| record.get(index))
+            .filter(|user| !user.is_empty())
+            .map(User::named);
+        let timestamp = match timestamp_index.and_then(|index| record.get(index)) {
+            Some(timestamp) if !timestamp.trim().is_empty() => match time::parse(timestamp) {
+                Some(timestamp) => Some(timestamp),
+                None => {
+                    bad_rows += 1;
+
+                    let line = record.position().map_or(0, csv::Position::line);
+                    warn!(
+                        "BAD LINE {:?}:{} invalid timestamp {:?}",
+                        file_path, line, timestamp
+                    );
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        // like in json, a score that isn't a number is no score, which weighs nothing
+        let score = score_index
+            .and_then(|index| record.get(index))
+            .and_then(|score| score.trim().parse().ok());
+        let matched = predicate_indices
+            .iter()
+            .enumerate()
+            .filter(|&(i, index)| record.get(*index) == Some(config.predicates[i].value()))
+            .fold(0, |matched, (i, _)| matched | 1 << i);
+
+        let meta = Meta {
+            user,
+            timestamp,
+            score,
+            matched,
+            ..Meta::default()
+        };
+        let post = match Post::new(config, &meta) {
+            Some(post) => post,
+            None => continue,
+        };
+
+        let texts = text_indices.iter().filter_map(|&index| record.get(index));
+
+        if dedup::is_duplicate(config, texts.clone()) {
+            continue;
+        }
+
+        // rows without a user are each their own anonymous user, so they are never capped
+        let mut seen = user
+            .filter(|_| config.per_user_cap.is_some())
+            .map(|user| seen.entry(user).or_default());
+
+        for text in texts {
+            count_phrases(config, &post, text, phrase_counts, seen.as_deref_mut());
+        }
+    }
+
+    if bad_rows == 0 {
+        Ok(())
+    } else {
+        Err(Failure::BadLines(bad_rows))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config, counts};
+    use crate::weight::Transform;
+
+    fn count_format(
+        args: &[&str],
+        format: Format,
+        file: &str,
+    ) -> (Result<(), Failure>, crate::Map) {
+        let config = config(args);
+        let mut phrase_counts = crate::Map::new();
+        let result = process_delimited(
+            &config,
+            format,
+            Path::new("a"),
+            file.as_bytes(),
+            &mut phrase_counts,
+        );
+
+        (result, phrase_counts)
+    }
+
+    fn count(args: &[&str], csv: &str) -> (Result<(), Failure>, crate::Map) {
+        count_format(args, Format::Csv, csv)
+    }
+
+    fn count_of(phrase_counts: &crate::Map, phrase: &str) -> u32 {
+        counts(phrase_counts, phrase).map_or(0, |counts| counts.count)
+    }
+
+    #[test]
+    fn text_columns() {
+        let csv = "id,text,title\n1,red,fox\n2,\"red, blue\",\n";
+
+        let (result, phrase_counts) = count(&[], csv);
+        assert!(result.is_ok());
+        assert_eq!(count_of(&phrase_counts, "red"), 2);
+        assert_eq!(count_of(&phrase_counts, "blue"), 1);
+        assert_eq!(count_of(&phrase_counts, "fox"), 0);
+        assert_eq!(count_of(&phrase_counts, "1"), 0);
+
+        let args = ["--text-column", "title", "--text-column", "1"];
+        let (result, phrase_counts) = count(&args, csv);
+        assert!(result.is_ok());
+        assert_eq!(count_of(&phrase_counts, "red"), 2);
+        assert_eq!(count_of(&phrase_counts, "fox"), 1);
+
+        let (result, phrase_counts) = count_format(&[], Format::Tsv, "id\ttext\n1\tred, blue\n");
+        assert!(result.is_ok());
+        assert_eq!(count_of(&phrase_counts, "red"), 1);
+        assert_eq!(count_of(&phrase_counts, "blue"), 1);
+    }
+
+    #[test]
+    fn missing_columns_fail_the_whole_file() {
+        for args in [
+            &["--text-column", "body"][..],
+            &["--text-column", "2"],
+            &["--user-column", "user"],
+            &["--where", "lang==en"],
+            &["--where", "meta.lang==en"],
+        ] {
+            let (result, phrase_counts) = count(args, "id,text\n1,red\n");
+            assert_eq!(
+                result.err().unwrap().reason(),
+                "missing-column",
+                "{:?}",
+                args
+            );
+            assert!(phrase_counts.is_empty());
+        }
+    }
+
+    #[test]
+    fn bad_rows_are_skipped() {
+        let args = ["--buckets", "day", "--timestamp-column", "time"];
+        let csv = "text,time\nred,2020-01-01\nblue,yesterday\nfox,\n";
+
+        let (result, phrase_counts) = count(&args, csv);
+        assert!(matches!(result, Err(Failure::BadLines(1))));
+        assert_eq!(count_of(&phrase_counts, "red"), 1);
+        assert_eq!(count_of(&phrase_counts, "blue"), 0);
+        assert_eq!(count_of(&phrase_counts, "fox"), 1);
+
+        // the timestamp column is only read if timestamps are used
+        let (result, phrase_counts) = count(&["--timestamp-column", "time"], csv);
+        assert!(result.is_ok());
+        assert_eq!(count_of(&phrase_counts, "blue"), 1);
+    }
+
+    #[test]
+    fn where_compares_columns() {
+        let csv = "text,lang\nred,en\nrot,de\nfox,\n";
+
+        let (result, phrase_counts) = count(&["--where", "lang==en"], csv);
+        assert!(result.is_ok());
+        assert_eq!(count_of(&phrase_counts, "red"), 1);
+        assert_eq!(count_of(&phrase_counts, "rot"), 0);
+        assert_eq!(count_of(&phrase_counts, "fox"), 0);
+
+        let (result, phrase_counts) = count(&["--where", "lang!=\"en\""], csv);
+        assert!(result.is_ok());
+        assert_eq!(count_of(&phrase_counts, "red"), 0);
+        assert_eq!(count_of(&phrase_counts, "rot"), 1);
+        assert_eq!(count_of(&phrase_counts, "fox"), 1);
+    }
+
+    #[test]
+    fn scores_that_are_not_numbers_weigh_nothing() {
+        let args = ["--score-column", "score", "--weight", "linear"];
+        let (result, phrase_counts) = count(&args, "text,score\nred fox,2.5\nred,n/a\nfox,\n");
+        let config = config(&args);
+
+        assert!(result.is_ok());
+        assert_eq!(counts(&phrase_counts, "red").unwrap().count, 2);
+        assert_eq!(
+            counts(&phrase_counts, "red").unwrap().total(&config),
+            Transform::Linear.weight(Some(2.5))
+        );
+        assert_eq!(
+            counts(&phrase_counts, "fox").unwrap().total(&config),
+            Transform::Linear.weight(Some(2.5))
+        );
+    }
+
+    #[test]
+    fn per_user_cap() {
+        let args = ["--user-column", "user", "--per-user-cap", "1"];
+        let csv = "user,text\na,red red\na,red\nb,red\n,red\n,red\n";
+
+        let (result, phrase_counts) = count(&args, csv);
+        assert!(result.is_ok());
+        // rows without a user are each their own user
+        assert_eq!(count_of(&phrase_counts, "red"), 4);
+    }
+}
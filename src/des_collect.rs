@@ -25,7 +25,7 @@ impl<'de> serde::de::Visitor<'de> for DesCollect<'_> {
         A: serde::de::MapAccess<'de>,
     {
         let phrase_counts = &mut *self.0;
-        while let Some((phrase, count)) = map.next_entry()? {
+        while let Some((phrase, count)) = map.next_entry::<&str, crate::post::Counts>()? {
             phrase_counts.add(phrase, count);
         }
 
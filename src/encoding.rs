@@ -0,0 +1,430 @@
+use encoding_rs::{Decoder, DecoderResult, Encoding};
+use log::warn;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::{FromStr, Utf8Error};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+pub static SKIPPED_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+pub static LOSSY_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+pub static TRANSCODED_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// how much of the previous input a `DecodeReader` keeps, the start of a utf-8 sequence
+/// that is only found to be malformed in the next input is never longer than this
+const CARRY: usize = 3;
+
+/// what to do with inputs that aren't valid utf-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Skip,
+    /// replace invalid sequences with U+FFFD
+    Lossy,
+    /// decode from `--encoding`, or from a guessed legacy encoding
+    Transcode,
+}
+
+impl Policy {
+    pub const NAMES: &'static [&'static str] = &["skip", "lossy", "transcode"];
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "skip" => Ok(Policy::Skip),
+            "lossy" => Ok(Policy::Lossy),
+            "transcode" => Ok(Policy::Transcode),
+            _ => Err(format!(
+                "unknown policy `{}`, expected one of {}",
+                name,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown encoding `{}`", label))
+}
+
+/// how an input that wasn't valid utf-8 was decoded
+#[derive(Debug, Clone, Copy)]
+pub enum Decoded {
+    Lossy,
+    Transcoded {
+        encoding: &'static Encoding,
+        detected: bool,
+        had_errors: bool,
+    },
+}
+
+impl Decoded {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Decoded::Lossy => "lossy",
+            Decoded::Transcoded { .. } => "transcoded",
+        }
+    }
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Decoded::Lossy => write!(f, "replaced invalid utf-8"),
+            Decoded::Transcoded {
+                encoding,
+                detected,
+                had_errors,
+            } => {
+                write!(
+                    f,
+                    "transcoded from {} {}",
+                    if detected { "detected" } else { "declared" },
+                    encoding.name()
+                )?;
+
+                if had_errors {
+                    write!(f, " with replacements")?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// where an input stopped being valid utf-8, the error of a `DecodeReader` with
+/// `--invalid-utf8 skip`
+#[derive(Debug)]
+pub struct InvalidUtf8(pub u64);
+
+impl fmt::Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid utf-8 at byte {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidUtf8 {}
+
+/// log and record that the input at `path` wasn't valid utf-8 from `offset` on
+pub fn report(path: &Path, offset: u64, decoded: &Decoded) {
+    warn!("DECODED {:?} {}, {}", path, InvalidUtf8(offset), decoded);
+    crate::failure::record_decoded(path, decoded);
+}
+
+/// decode `bytes`, which failed utf-8 validation with `err`, according to the policy
+pub fn decode(
+    config: &crate::Config,
+    bytes: &[u8],
+    err: Utf8Error,
+) -> Result<(String, Decoded), Utf8Error> {
+    let decoded = match config.invalid_utf8 {
+        Policy::Skip => {
+            SKIPPED_FILE_COUNT.fetch_add(1, Relaxed);
+            return Err(err);
+        }
+        Policy::Lossy => {
+            LOSSY_FILE_COUNT.fetch_add(1, Relaxed);
+            (String::from_utf8_lossy(bytes).into_owned(), Decoded::Lossy)
+        }
+        Policy::Transcode => {
+            let (encoding, detected) = match config.encoding {
+                Some(encoding) => (encoding, false),
+                None => {
+                    let mut detector = chardetng::EncodingDetector::new();
+                    detector.feed(bytes, true);
+                    (detector.guess(None, true), true)
+                }
+            };
+
+            let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+
+            TRANSCODED_FILE_COUNT.fetch_add(1, Relaxed);
+            (
+                text.into_owned(),
+                Decoded::Transcoded {
+                    encoding,
+                    detected,
+                    had_errors,
+                },
+            )
+        }
+    };
+
+    Ok(decoded)
+}
+
+/// streams valid utf-8, and decodes the rest of the input according to the policy once
+/// it isn't, for files that are too large to be checked up front
+pub struct DecodeReader<'a, R> {
+    inner: R,
+    config: &'a crate::Config,
+    path: &'a Path,
+    decoder: Decoder,
+    /// set once invalid utf-8 was found and the policy lets decoding go on
+    decoded: Option<(u64, Decoded)>,
+    /// starts with up to `CARRY` bytes of the previous input, which were already decoded
+    input: Box<[u8]>,
+    input_start: usize,
+    input_end: usize,
+    /// the offset of `input` in the file
+    offset: u64,
+    eof: bool,
+    output: Box<[u8]>,
+    output_start: usize,
+    output_end: usize,
+}
+
+impl<'a, R: Read> DecodeReader<'a, R> {
+    pub fn new(config: &'a crate::Config, path: &'a Path, inner: R) -> Self {
+        Self {
+            inner,
+            config,
+            path,
+            decoder: encoding_rs::UTF_8.new_decoder_without_bom_handling(),
+            decoded: None,
+            input: vec![0; 1 << 16].into_boxed_slice(),
+            input_start: 0,
+            input_end: 0,
+            offset: 0,
+            eof: false,
+            output: vec![0; 1 << 17].into_boxed_slice(),
+            output_start: 0,
+            output_end: 0,
+        }
+    }
+
+    /// decode the next part of the input into `output`, false once everything was decoded
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.input_start == self.input_end {
+            if self.eof {
+                return Ok(false);
+            }
+
+            let carry = self.input_end.min(CARRY);
+            self.input
+                .copy_within(self.input_end - carry..self.input_end, 0);
+            self.offset += (self.input_end - carry) as u64;
+            self.input_start = carry;
+            self.input_end = carry + self.inner.read(&mut self.input[carry..])?;
+            self.eof = self.input_end == carry;
+        }
+
+        let input = &self.input[self.input_start..self.input_end];
+        self.output_start = 0;
+
+        if let Some((_, decoded)) = &mut self.decoded {
+            let (_, read, written, had_errors) =
+                self.decoder
+                    .decode_to_utf8(input, &mut self.output, self.eof);
+
+            if let Decoded::Transcoded {
+                had_errors: errors, ..
+            } = decoded
+            {
+                *errors |= had_errors;
+            }
+
+            self.input_start += read;
+            self.output_end = written;
+            return Ok(true);
+        }
+
+        let (result, read, written) =
+            self.decoder
+                .decode_to_utf8_without_replacement(input, &mut self.output, self.eof);
+        self.output_end = written;
+
+        let (bad, extra) = match result {
+            DecoderResult::Malformed(bad, extra) => (bad, extra),
+            DecoderResult::InputEmpty | DecoderResult::OutputFull => {
+                self.input_start += read;
+                return Ok(true);
+            }
+        };
+
+        // the malformed bytes may have started in the previous input, which is carried over
+        let bad_start = (self.input_start + read).saturating_sub(usize::from(bad + extra));
+        let offset = self.offset + bad_start as u64;
+
+        match self.config.invalid_utf8 {
+            Policy::Skip => {
+                SKIPPED_FILE_COUNT.fetch_add(1, Relaxed);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    InvalidUtf8(offset),
+                ));
+            }
+            Policy::Lossy => {
+                LOSSY_FILE_COUNT.fetch_add(1, Relaxed);
+                self.decoded = Some((offset, Decoded::Lossy));
+                self.input_start += read;
+
+                let replacement = '\u{FFFD}'.encode_utf8(&mut [0; 4]).len();
+                '\u{FFFD}'.encode_utf8(&mut self.output[written..written + replacement]);
+                self.output_end += replacement;
+            }
+            Policy::Transcode => {
+                let (encoding, detected) = match self.config.encoding {
+                    Some(encoding) => (encoding, false),
+                    // only the rest of this part of the input is there to guess from
+                    None => {
+                        let mut detector = chardetng::EncodingDetector::new();
+                        detector.feed(&self.input[bad_start..self.input_end], self.eof);
+                        (detector.guess(None, true), true)
+                    }
+                };
+
+                TRANSCODED_FILE_COUNT.fetch_add(1, Relaxed);
+                self.decoder = encoding.new_decoder_without_bom_handling();
+                self.decoded = Some((
+                    offset,
+                    Decoded::Transcoded {
+                        encoding,
+                        detected,
+                        had_errors: false,
+                    },
+                ));
+                self.input_start = bad_start;
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DecodeReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_start == self.output_end {
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.output_end - self.output_start);
+        buf[..len].copy_from_slice(&self.output[self.output_start..self.output_start + len]);
+        self.output_start += len;
+
+        Ok(len)
+    }
+}
+
+/// the input is only reported as decoded once it is done with, so that the report knows
+/// if transcoding needed replacements
+impl<R> Drop for DecodeReader<'_, R> {
+    fn drop(&mut self) {
+        if let Some((offset, decoded)) = &self.decoded {
+            report(self.path, *offset, decoded);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// reads at most `size` bytes at a time, so that sequences are split between reads
+    struct Chunks<'a> {
+        bytes: &'a [u8],
+        size: usize,
+    }
+
+    impl Read for Chunks<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.size.min(buf.len()).min(self.bytes.len());
+            buf[..len].copy_from_slice(&self.bytes[..len]);
+            self.bytes = &self.bytes[len..];
+            Ok(len)
+        }
+    }
+
+    const SIZES: [usize; 5] = [1, 2, 3, 7, 1 << 20];
+
+    fn decode_all(args: &[&str], bytes: &[u8], size: usize) -> io::Result<String> {
+        let config = crate::tests::config(args);
+        let mut text = String::new();
+
+        DecodeReader::new(&config, Path::new("test"), Chunks { bytes, size })
+            .read_to_string(&mut text)?;
+
+        Ok(text)
+    }
+
+    fn invalid_at(bytes: &[u8], size: usize) -> u64 {
+        let err = decode_all(&[], bytes, size).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<InvalidUtf8>())
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn valid_utf8_is_unchanged() {
+        let text = "héllo wörld, 日本語 🎉\n".repeat(5_000);
+
+        for size in SIZES {
+            assert_eq!(decode_all(&[], text.as_bytes(), size).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn skip_reports_where_the_input_became_invalid() {
+        for size in SIZES {
+            assert_eq!(invalid_at(b"abc\xffdef", size), 3);
+            // the start of a sequence that is cut short by the next byte
+            assert_eq!(invalid_at(b"ab\xc3(", size), 2);
+            assert_eq!(invalid_at(b"ab\xf0\x9f\x8e(", size), 2);
+            assert_eq!(invalid_at(b"ab\xf0\x9f\x8e", size), 2);
+        }
+    }
+
+    #[test]
+    fn skip_offsets_count_earlier_reads() {
+        let mut bytes = "é".repeat(50_000).into_bytes();
+        bytes.extend_from_slice(b"\xc3(");
+
+        for size in [1, 4_096, 65_537, 1 << 20] {
+            assert_eq!(invalid_at(&bytes, size), 100_000);
+        }
+    }
+
+    #[test]
+    fn lossy_is_the_same_as_in_memory() {
+        let lossy = ["--invalid-utf8", "lossy"];
+
+        for bytes in [
+            &b"ab\xc3(d"[..],
+            b"a\xffb\xfe\xfdc",
+            b"\xf0\x9f\x8e",
+            b"\xe6\x97 ok",
+        ] {
+            for size in SIZES {
+                assert_eq!(
+                    decode_all(&lossy, bytes, size).unwrap(),
+                    String::from_utf8_lossy(bytes)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn transcode_starts_over_at_the_invalid_sequence() {
+        let latin1 = ["--invalid-utf8", "transcode", "--encoding", "latin1"];
+
+        for size in SIZES {
+            assert_eq!(
+                decode_all(&latin1, b"caf\xe9 cr\xe8me", size).unwrap(),
+                "café crème"
+            );
+            // what was valid utf-8 before stays decoded as utf-8
+            assert_eq!(
+                decode_all(&latin1, b"\xc3\xa9 caf\xe9", size).unwrap(),
+                "é café"
+            );
+        }
+    }
+}
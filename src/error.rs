@@ -0,0 +1,107 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::Mutex;
+
+/// set once a background job failed and the run can't finish
+static ABORTED: AtomicBool = AtomicBool::new(false);
+static FIRST_ERROR: Mutex<Option<Error>> = Mutex::new(None);
+
+pub const EXIT_SETUP: i32 = 1;
+pub const EXIT_INPUT: i32 = 2;
+pub const EXIT_IO: i32 = 3;
+pub const EXIT_NO_SPACE: i32 = 4;
+
+/// errors that stop the whole run, failures of single inputs are a `Failure`
+#[derive(Debug)]
+pub enum Error {
+    /// logging or a thread pool couldn't be set up
+    Setup(String),
+    /// the list of inputs couldn't be read
+    Input { path: PathBuf, source: io::Error },
+    /// a temp file or the output couldn't be created, written or read
+    Io {
+        action: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// some temp files couldn't be read back with --continue-on-error
+    Incomplete { temp_files: usize },
+}
+
+impl Error {
+    pub fn io(action: &'static str, path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Error::Io {
+            action,
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Setup(_) => EXIT_SETUP,
+            Error::Input { .. } => EXIT_INPUT,
+            Error::Io { source, .. } if is_out_of_space(source) => EXIT_NO_SPACE,
+            Error::Io { .. } | Error::Incomplete { .. } => EXIT_IO,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Setup(message) => f.write_str(message),
+            Error::Input { path, source } => write!(f, "cannot read {:?}: {}", path, source),
+            Error::Io {
+                action,
+                path,
+                source,
+            } => write!(f, "cannot {} {:?}: {}", action, path, source),
+            Error::Incomplete { temp_files } => write!(
+                f,
+                "the counts of {} temp files are missing from the output",
+                temp_files
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn is_out_of_space(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::StorageFull
+}
+
+/// bincode wraps io errors, everything else means the temp file is corrupt
+pub fn from_bincode(err: bincode::ErrorKind) -> io::Error {
+    match err {
+        bincode::ErrorKind::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+/// stop processing new inputs, the first error is returned at the end of the run
+pub fn abort(err: Error) {
+    let mut first = FIRST_ERROR
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    if first.is_none() {
+        *first = Some(err);
+    }
+
+    ABORTED.store(true, Relaxed);
+}
+
+pub fn is_aborted() -> bool {
+    ABORTED.load(Relaxed)
+}
+
+pub fn take() -> Option<Error> {
+    FIRST_ERROR
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take()
+}
@@ -0,0 +1,220 @@
+use serde::Serialize;
+use serde_json::error::Category;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::Mutex;
+
+use crate::encoding::InvalidUtf8;
+
+static REPORT: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+pub static FAILED_INPUT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// why an input could not be (fully) counted
+pub enum Failure {
+    Open(io::Error),
+    Read(io::Error),
+    /// the input wasn't valid utf-8 from `offset` on
+    InvalidUtf8 {
+        offset: u64,
+    },
+    /// some lines of an ndjson file, or rows of a csv file, were skipped
+    BadLines(usize),
+    /// a csv column that was asked for isn't in the header, so nothing was counted
+    MissingColumn(String),
+    Empty,
+    /// the document ended in the middle of a value
+    Truncated(serde_json::Error),
+    Syntax(serde_json::Error),
+    /// valid json, but it doesn't have the shape the selectors expect
+    Shape(serde_json::Error),
+}
+
+#[derive(Serialize)]
+struct Entry {
+    input: String,
+    reason: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+    message: String,
+}
+
+impl From<io::Error> for Failure {
+    /// a streamed input that wasn't valid utf-8 fails as a read error of the decoder
+    fn from(err: io::Error) -> Self {
+        match err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<InvalidUtf8>())
+        {
+            Some(&InvalidUtf8(offset)) => Failure::InvalidUtf8 { offset },
+            None => Failure::Read(err),
+        }
+    }
+}
+
+impl Failure {
+    pub fn from_json(err: serde_json::Error) -> Self {
+        match err.classify() {
+            Category::Io => io::Error::from(err).into(),
+            Category::Eof => Failure::Truncated(err),
+            Category::Syntax => Failure::Syntax(err),
+            Category::Data => Failure::Shape(err),
+        }
+    }
+
+    /// machine readable name, used in the error report
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Failure::Open(_) => "open",
+            Failure::Read(_) => "read",
+            Failure::InvalidUtf8 { .. } => "invalid-utf8",
+            Failure::BadLines(_) => "bad-lines",
+            Failure::MissingColumn(_) => "missing-column",
+            Failure::Empty => "empty",
+            Failure::Truncated(_) => "truncated",
+            Failure::Syntax(_) => "syntax",
+            Failure::Shape(_) => "shape",
+        }
+    }
+
+    /// human readable name, used in the logs
+    pub fn label(&self) -> &'static str {
+        match self {
+            Failure::Open(_) => "CANNOT OPEN",
+            Failure::Read(_) => "CANNOT READ",
+            Failure::InvalidUtf8 { .. } => "INVALID UTF-8",
+            Failure::BadLines(_) => "BAD LINES",
+            Failure::MissingColumn(_) => "MISSING COLUMN",
+            Failure::Empty => "EMPTY FILE",
+            Failure::Truncated(_) => "TRUNCATED",
+            Failure::Syntax(_) => "SYNTAX ERROR",
+            Failure::Shape(_) => "UNEXPECTED SHAPE",
+        }
+    }
+
+    fn position(&self) -> Option<(usize, usize)> {
+        match self {
+            Failure::Truncated(err) | Failure::Syntax(err) | Failure::Shape(err) => {
+                Some((err.line(), err.column()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Open(err) | Failure::Read(err) => err.fmt(f),
+            Failure::InvalidUtf8 { offset } => InvalidUtf8(*offset).fmt(f),
+            Failure::BadLines(count) => write!(f, "{} bad lines", count),
+            Failure::MissingColumn(column) => write!(f, "missing column {}", column),
+            Failure::Empty => write!(f, "empty file"),
+            Failure::Truncated(err) | Failure::Syntax(err) | Failure::Shape(err) => err.fmt(f),
+        }
+    }
+}
+
+fn push(entry: Entry) {
+    REPORT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push(entry);
+}
+
+pub fn record(input: &Path, failure: &Failure) {
+    FAILED_INPUT_COUNT.fetch_add(1, Relaxed);
+
+    let position = failure.position();
+
+    push(Entry {
+        input: input.display().to_string(),
+        reason: failure.reason(),
+        line: position.map(|(line, _)| line),
+        column: position.map(|(_, column)| column),
+        message: failure.to_string(),
+    });
+}
+
+/// inputs that were counted, but not as they were on disk
+pub fn record_decoded(input: &Path, decoded: &crate::encoding::Decoded) {
+    push(Entry {
+        input: input.display().to_string(),
+        reason: decoded.reason(),
+        line: None,
+        column: None,
+        message: decoded.to_string(),
+    });
+}
+
+/// write every recorded failure as a json array
+pub fn write_report(path: &Path) -> io::Result<()> {
+    let mut report = REPORT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    report.sort_by(|a, b| a.input.cmp(&b.input));
+
+    let mut file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut file, &*report)?;
+    writeln!(file)?;
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(json: &[u8]) -> Failure {
+        Failure::from_json(serde_json::from_slice::<Vec<u32>>(json).unwrap_err())
+    }
+
+    /// a reader that fails after its data
+    struct Failing(&'static [u8], fn() -> io::Error);
+
+    impl io::Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err((self.1)());
+            }
+
+            let read = self.0.len().min(buf.len());
+            buf[..read].copy_from_slice(&self.0[..read]);
+            self.0 = &self.0[read..];
+            Ok(read)
+        }
+    }
+
+    fn classify_read(reader: Failing) -> Failure {
+        Failure::from_json(serde_json::from_reader::<_, Vec<u32>>(reader).unwrap_err())
+    }
+
+    #[test]
+    fn json_errors() {
+        assert_eq!(classify(b"[1, 2").reason(), "truncated");
+        assert_eq!(classify(b"").reason(), "truncated");
+        assert_eq!(classify(b"[1 2]").reason(), "syntax");
+        assert_eq!(classify(b"[1, \"2\"]").reason(), "shape");
+        assert_eq!(classify(b"{}").reason(), "shape");
+
+        assert_eq!(classify(b"[1,\n 2 3]").position(), Some((2, 4)));
+    }
+
+    #[test]
+    fn json_read_errors() {
+        let failure = classify_read(Failing(b"[1, ", || {
+            io::Error::new(io::ErrorKind::InvalidData, InvalidUtf8(4))
+        }));
+        assert!(matches!(failure, Failure::InvalidUtf8 { offset: 4 }));
+        assert_eq!(failure.position(), None);
+
+        let failure = classify_read(Failing(b"[1, ", || {
+            io::Error::from(io::ErrorKind::UnexpectedEof)
+        }));
+        assert_eq!(failure.reason(), "read");
+    }
+}
@@ -0,0 +1,517 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use itertools::Either;
+use serde::Deserialize;
+use walkdir::{DirEntry, WalkDir};
+
+pub const STDIN: &str = "-";
+
+#[derive(Debug)]
+pub enum Input {
+    File(PathBuf),
+    /// standard input, requested with `-`
+    Stdin,
+    /// an archive whose json members are processed as if they were files
+    Archive(PathBuf, ArchiveKind),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArchiveKind {
+    /// a tarball, optionally compressed (`.tar`, `.tar.gz`, `.tgz`, `.tar.zst`, ...)
+    Tar,
+    Zip,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// a single json document per input
+    Json,
+    /// newline delimited json, one document per line
+    Ndjson,
+    /// plain text, the whole file is counted
+    Text,
+    /// plain text that may have markdown syntax stripped before it is counted
+    Markdown,
+    /// comma separated values, the text columns are counted
+    Csv,
+    /// tab separated values, the text columns are counted
+    Tsv,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl Input {
+    /// classify `path`, returns `None` if it is neither an archive nor a json file
+    pub fn of_path(path: PathBuf) -> Option<Self> {
+        if let Some(kind) = ArchiveKind::of_path(&path) {
+            return Some(Input::Archive(path, kind));
+        }
+
+        Format::of_path(&path)?;
+        Some(Input::File(path))
+    }
+
+    /// classify a path listed in a manifest, these are assumed to be wanted even if
+    /// their extension isn't recognized
+    pub fn from_manifest(path: PathBuf) -> Self {
+        match ArchiveKind::of_path(&path) {
+            Some(kind) => Input::Archive(path, kind),
+            None => Input::File(path),
+        }
+    }
+
+    pub fn is_archive(&self) -> bool {
+        matches!(self, Input::Archive(..))
+    }
+}
+
+impl ArchiveKind {
+    pub fn of_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "zip" => return Some(ArchiveKind::Zip),
+            "tar" | "tgz" => return Some(ArchiveKind::Tar),
+            _ => (),
+        }
+
+        match Compression::of_path(path) {
+            Compression::None => None,
+            _ => match Path::new(path.file_stem()?).extension()?.to_str()? {
+                "tar" => Some(ArchiveKind::Tar),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Format {
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "json" => Some(Format::Json),
+            "jsonl" | "ndjson" => Some(Format::Ndjson),
+            "txt" | "text" => Some(Format::Text),
+            "md" | "markdown" => Some(Format::Markdown),
+            "csv" => Some(Format::Csv),
+            "tsv" | "tab" => Some(Format::Tsv),
+            _ => None,
+        }
+    }
+
+    /// the format of `path`, ignoring any compression extension (`posts.json.gz` is json)
+    pub fn of_path(path: &Path) -> Option<Self> {
+        let path = match Compression::of_path(path) {
+            Compression::None => path,
+            _ => Path::new(path.file_stem()?),
+        };
+
+        Self::from_extension(path.extension()?.to_str()?)
+    }
+
+    /// guess the format from the contents, if the first line is a complete json value
+    /// and there is more after it then this is ndjson, pretty printed documents never
+    /// have a complete value on the first line
+    pub fn sniff(contents: &str) -> Self {
+        let contents = contents.trim_start();
+        let (first_line, rest) = match contents.find('\n') {
+            Some(end) => contents.split_at(end),
+            None => return Format::Json,
+        };
+
+        let mut deserializer = serde_json::Deserializer::from_str(first_line);
+        let first_line_is_value = serde::de::IgnoredAny::deserialize(&mut deserializer)
+            .and_then(|_| deserializer.end())
+            .is_ok();
+
+        if first_line_is_value && !rest.trim().is_empty() {
+            Format::Ndjson
+        } else {
+            Format::Json
+        }
+    }
+
+    /// `contents` (or the start of them) is used to tell json from ndjson, since `.json`
+    /// exports are often one object per line, without it json is assumed
+    pub fn detect(path: &Path, config: &crate::Config, contents: Option<&str>) -> Self {
+        match Self::of_path(path) {
+            Some(Format::Json) | None if config.ndjson => Format::Ndjson,
+            Some(Format::Json) | None => contents.map_or(Format::Json, Self::sniff),
+            Some(format) => format,
+        }
+    }
+}
+
+impl Compression {
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "gz" | "gzip" => Some(Compression::Gzip),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            "bz2" | "bzip2" => Some(Compression::Bzip2),
+            _ => None,
+        }
+    }
+
+    pub fn of_path(path: &Path) -> Self {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(Self::from_extension)
+            .unwrap_or(Compression::None)
+    }
+
+    pub fn from_magic(bytes: &[u8]) -> Self {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if bytes.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+
+    pub fn decoder<'a, R: BufRead + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+            Compression::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(reader)),
+        })
+    }
+}
+
+/// wrap `reader` so that it is transparently decompressed, the compression is detected
+/// from the leading magic bytes so mislabeled files are still read correctly
+pub fn decompress<'a, R: Read + 'a>(reader: R) -> io::Result<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::new(reader);
+    let compression = Compression::from_magic(reader.fill_buf()?);
+    compression.decoder(reader)
+}
+
+/// like `decompress`, with the size of what is read from it if that is known up front,
+/// i.e. `size` if the data isn't compressed
+pub fn decompress_sized<'a, R: Read + 'a>(
+    reader: R,
+    size: u64,
+) -> io::Result<(Box<dyn Read + 'a>, Option<u64>)> {
+    let mut reader = BufReader::new(reader);
+    let compression = Compression::from_magic(reader.fill_buf()?);
+    let size = Some(size).filter(|_| compression == Compression::None);
+
+    Ok((compression.decoder(reader)?, size))
+}
+
+pub fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    decompress(File::open(path)?)
+}
+
+/// like `open`, with the size of the file if it isn't compressed
+pub fn open_sized(path: &Path) -> io::Result<(Box<dyn Read>, Option<u64>)> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    decompress_sized(file, size)
+}
+
+#[derive(Debug, Default)]
+pub struct Filter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    skip_hidden: bool,
+}
+
+impl Filter {
+    pub fn new(
+        include: &[String],
+        exclude: &[String],
+        skip_hidden: bool,
+    ) -> Result<Self, globset::Error> {
+        fn build(globs: &[String]) -> Result<GlobSet, globset::Error> {
+            let mut set = GlobSetBuilder::new();
+
+            for glob in globs {
+                set.add(GlobBuilder::new(glob).literal_separator(true).build()?);
+            }
+
+            set.build()
+        }
+
+        Ok(Self {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(build(include)?)
+            },
+            exclude: build(exclude)?,
+            skip_hidden,
+        })
+    }
+
+    /// globs may match either the name of the entry or its path relative to `root`
+    fn is_match(set: &GlobSet, root: &Path, dir_entry: &DirEntry) -> bool {
+        set.is_match(dir_entry.file_name())
+            || dir_entry
+                .path()
+                .strip_prefix(root)
+                .is_ok_and(|path| set.is_match(path))
+    }
+
+    /// if a folder should be descended into, or a file should be processed
+    fn accepts(&self, root: &Path, dir_entry: &DirEntry) -> bool {
+        // the folders that were asked for are always walked
+        if dir_entry.depth() == 0 && dir_entry.file_type().is_dir() {
+            return true;
+        }
+
+        if self.skip_hidden
+            && dir_entry.depth() != 0
+            && dir_entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with('.'))
+        {
+            return false;
+        }
+
+        if Self::is_match(&self.exclude, root, dir_entry) {
+            return false;
+        }
+
+        if dir_entry.file_type().is_dir() {
+            return true;
+        }
+
+        if !dir_entry.file_type().is_file() {
+            return false;
+        }
+
+        let path = dir_entry.path();
+
+        if Format::of_path(path).is_none() && ArchiveKind::of_path(path).is_none() {
+            return false;
+        }
+
+        self.include
+            .as_ref()
+            .is_none_or(|include| Self::is_match(include, root, dir_entry))
+    }
+}
+
+/// every file below `root` that should be processed
+pub fn walk(root: PathBuf, config: &crate::Config) -> impl Iterator<Item = Input> + '_ {
+    let mut walk_dir = WalkDir::new(&root).follow_links(config.follow_symlinks);
+
+    if let Some(max_depth) = config.max_depth {
+        walk_dir = walk_dir.max_depth(max_depth.saturating_add(1));
+    }
+
+    walk_dir
+        .into_iter()
+        .filter_entry(move |dir_entry| config.filter.accepts(&root, dir_entry))
+        .flatten()
+        .filter(|dir_entry| dir_entry.file_type().is_file())
+        .filter_map(|dir_entry| Input::of_path(dir_entry.into_path()))
+}
+
+/// read a list of paths separated by newlines, or by NULs if there are any (`find -print0`)
+pub fn read_manifest(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut manifest = String::new();
+
+    if path == Path::new(STDIN) {
+        io::stdin().read_to_string(&mut manifest)?;
+    } else {
+        File::open(path)?.read_to_string(&mut manifest)?;
+    }
+
+    let paths = if manifest.contains('\0') {
+        Either::Left(manifest.split('\0'))
+    } else {
+        Either::Right(manifest.lines())
+    };
+
+    Ok(paths
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// the longest valid utf-8 prefix of `bytes`, to sniff the format of a streamed file
+pub fn utf8_prefix(bytes: &[u8]) -> &str {
+    match std::str::from_utf8(bytes) {
+        Ok(prefix) => prefix,
+        Err(err) => std::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or_default(),
+    }
+}
+
+/// consume leading whitespace, returns true if there was nothing else
+pub fn skip_whitespace(reader: &mut impl BufRead) -> io::Result<bool> {
+    loop {
+        let buf = reader.fill_buf()?;
+
+        if buf.is_empty() {
+            return Ok(true);
+        }
+
+        let whitespace = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        let done = whitespace < buf.len();
+        reader.consume(whitespace);
+
+        if done {
+            return Ok(false);
+        }
+    }
+}
+
+/// memory map the file at `path`, returns `None` if it is compressed
+pub fn map(path: &Path) -> io::Result<Option<memmap2::Mmap>> {
+    let file = File::open(path)?;
+
+    // SAFETY: the mapping is only ever read, `--mmap` documents that the inputs must
+    // not be modified while they are mapped
+    let mapping = unsafe { memmap2::Mmap::map(&file)? };
+
+    match Compression::from_magic(&mapping) {
+        Compression::None => Ok(Some(mapping)),
+        _ => Ok(None),
+    }
+}
+
+pub fn stdin() -> io::Result<Box<dyn Read>> {
+    decompress(io::stdin())
+}
+
+/// call `f` with every json member of the archive at `path` and its size if it isn't
+/// compressed, members are named by joining their path inside the archive onto `path`
+pub fn for_each_member(
+    path: &Path,
+    kind: ArchiveKind,
+    mut f: impl FnMut(&Path, &mut dyn Read, Option<u64>),
+) -> io::Result<()> {
+    match kind {
+        ArchiveKind::Tar => {
+            let mut archive = tar::Archive::new(open(path)?);
+
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+
+                let member_path = path.join(entry.path()?);
+
+                if Format::of_path(&member_path).is_none() {
+                    continue;
+                }
+
+                let size = entry.size();
+                let (mut member, size) = decompress_sized(&mut entry, size)?;
+                f(&member_path, &mut member, size);
+            }
+        }
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(File::open(path)?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+            for i in 0..archive.len() {
+                let mut member = archive
+                    .by_index(i)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+                if !member.is_file() {
+                    continue;
+                }
+
+                let member_path = match member.enclosed_name() {
+                    Some(name) => path.join(name),
+                    None => continue,
+                };
+
+                if Format::of_path(&member_path).is_none() {
+                    continue;
+                }
+
+                let size = member.size();
+                let (mut member, size) = decompress_sized(&mut member, size)?;
+                f(&member_path, &mut member, size);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn sniff_ndjson() {
+        assert_eq!(Format::sniff("{\"a\": 1}\n{\"a\": 2}\n"), Format::Ndjson);
+        assert_eq!(
+            Format::sniff("\n\n{\"a\": 1}\r\n{\"a\": 2}"),
+            Format::Ndjson
+        );
+        assert_eq!(Format::sniff("[1]\n[2]\n"), Format::Ndjson);
+    }
+
+    #[test]
+    fn sniff_json() {
+        assert_eq!(Format::sniff("{\n  \"a\": 1\n}\n"), Format::Json);
+        assert_eq!(Format::sniff("[\n{\"a\": 1},\n{\"a\": 2}\n]"), Format::Json);
+        assert_eq!(Format::sniff("{\"a\": 1}\n"), Format::Json);
+        assert_eq!(Format::sniff("{\"a\": 1}"), Format::Json);
+        assert_eq!(Format::sniff(""), Format::Json);
+    }
+
+    #[test]
+    fn manifest_lines() {
+        let mut manifest = tempfile::NamedTempFile::new().unwrap();
+        write!(manifest, "a.json\n\nsome dir/b.json\r\n").unwrap();
+
+        assert_eq!(
+            read_manifest(manifest.path()).unwrap(),
+            [PathBuf::from("a.json"), PathBuf::from("some dir/b.json")]
+        );
+    }
+
+    #[test]
+    fn manifest_nuls() {
+        let mut manifest = tempfile::NamedTempFile::new().unwrap();
+        write!(manifest, "a.json\0new\nline.json\0").unwrap();
+
+        assert_eq!(
+            read_manifest(manifest.path()).unwrap(),
+            [PathBuf::from("a.json"), PathBuf::from("new\nline.json")]
+        );
+    }
+
+    #[test]
+    fn missing_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_manifest(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn compressed_data_has_no_size() {
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(b"{\"a\": 1}").unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let (mut reader, size) = decompress_sized(&gzip[..], gzip.len() as u64).unwrap();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        assert_eq!((text.as_str(), size), ("{\"a\": 1}", None));
+
+        let (_, size) = decompress_sized(&b"{\"a\": 1}"[..], 8).unwrap();
+        assert_eq!(size, Some(8));
+    }
+}
@@ -1,40 +1,340 @@
 use bincode::config::Options;
 use hashbrown::HashMap;
+use itertools::Either;
 use log::{error, info, warn};
 use rayon::prelude::*;
 use serde::de::DeserializeSeed;
 use structopt::StructOpt;
-use walkdir::WalkDir;
 
 use std::cmp::Reverse;
 use std::collections::BTreeMap;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::Path;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering::Relaxed};
+use std::sync::Mutex;
 use std::time::Instant;
 
+use error::Error;
+use failure::Failure;
+
+mod dedup;
+mod delimited;
 mod des_collect;
+mod encoding;
+mod error;
+mod failure;
+mod input;
 mod map;
+mod post;
 mod proc_file;
-
-mod config {
-    include!(concat!(env!("OUT_DIR"), "/out.rs"));
-}
+mod selector;
+mod text;
+mod time;
+mod trends;
+mod users;
+mod weight;
 
 static FILE_PROCESED_COUNT: AtomicU32 = AtomicU32::new(1);
 static TOTAL_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
 static TEMP_FILE_COUNT: AtomicU32 = AtomicU32::new(0);
+/// maps that couldn't be spilled with --continue-on-error, merged at the end
+static UNSPILLED: Mutex<Vec<Map>> = Mutex::new(Vec::new());
+
+/// a first line of a streamed file that is longer than this is taken to be the start of a
+/// single json document, and not of ndjson
+const MAX_FIRST_LINE: u64 = 1 << 26;
 
-type Phrase<'a> = [&'a str; config::WORD_COUNT];
-type PhraseBuf = [Box<str>; config::WORD_COUNT];
-type Map = HashMap<PhraseBuf, u32>;
+/// the words of a phrase separated by spaces, in one allocation, words never contain
+/// whitespace
+type PhraseBuf = Box<str>;
+type Map = HashMap<PhraseBuf, post::Counts>;
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = concat!("search_posts"), about = "Count the number of times all n-grams occurs in a set of json files")]
-struct Config {
-    #[structopt(help("folders containing json files to be processed"))]
+#[structopt(
+    name = concat!("search_posts"),
+    about = "Count the number of times all n-grams occurs in a set of json files",
+    after_help = "EXIT CODES:\n    0    success\n    1    invalid arguments, or logging or the thread pools couldn't be set up\n    2    some inputs failed, or --files-from or --users-from couldn't be read\n    3    a temp file or the output couldn't be written or read\n    4    out of disk space"
+)]
+pub struct Config {
+    #[structopt(help(
+        "folders containing json, csv, plain text or markdown files to be processed, or - to read from stdin, files compressed with gzip, zstd or bzip2 (.gz, .zst, .bz2) are decompressed on the fly and tar or zip archives (.tar, .tar.gz, .zip) are read member by member"
+    ))]
     folders: Vec<std::path::PathBuf>,
 
+    #[structopt(
+        long,
+        help("read the paths of the files to process from this file (or - for stdin), one per line or NUL separated, these are not filtered by extension")
+    )]
+    files_from: Option<std::path::PathBuf>,
+
+    #[structopt(
+        long,
+        number_of_values(1),
+        help("only process files whose name (or path relative to the folder) matches one of these globs, may be repeated")
+    )]
+    include: Vec<String>,
+
+    #[structopt(
+        long,
+        number_of_values(1),
+        help("skip files and folders whose name (or path relative to the folder) matches one of these globs, may be repeated, e.g. _tmp or *.partial.json")
+    )]
+    exclude: Vec<String>,
+
+    #[structopt(long, help("skip files and folders whose name starts with a ."))]
+    skip_hidden: bool,
+
+    #[structopt(long, help("only descend this many folders below each folder"))]
+    max_depth: Option<usize>,
+
+    #[structopt(long, help("follow symbolic links while looking for files"))]
+    follow_symlinks: bool,
+
+    #[structopt(
+        long,
+        short,
+        default_value = "1",
+        help("number of words in each phrase")
+    )]
+    n: std::num::NonZeroUsize,
+
+    #[structopt(
+        long,
+        help("count every phrase length from 1 up to --n in the same pass, each length is written to its own output file, e.g. out.1.txt, out.2.txt")
+    )]
+    all_orders: bool,
+
+    #[structopt(
+        long,
+        help("also write the number of distinct posts that contain each phrase, as a second column after the count")
+    )]
+    document_frequency: bool,
+
+    #[structopt(
+        long,
+        possible_values(users::Mode::NAMES),
+        help("also write the number of distinct users of each phrase, as a column after the counts, approximate switches to a hyperloglog sketch (about 1.6% error, at most 4 KiB per phrase) for phrases with many users")
+    )]
+    distinct_users: Option<users::Mode>,
+
+    #[structopt(
+        long,
+        number_of_values(1),
+        help("path to the user id of each post, e.g. author, or to values that each belong to a different user, e.g. users[*], may be repeated, the user id must be inside the post's object or directly on an element that holds the user's posts, e.g. users[*].id, before the posts [default: from --schema]")
+    )]
+    user: Vec<selector::UserSelector>,
+
+    #[structopt(
+        long,
+        possible_values(time::Bucket::NAMES),
+        help("count phrases per hour, day, week or month (in utc) of the posts' timestamps, the output becomes a table with a row per phrase and a column per bucket")
+    )]
+    buckets: Option<time::Bucket>,
+
+    #[structopt(
+        long,
+        number_of_values(1),
+        help("path to the timestamp of each post, unix seconds or milliseconds, rfc 3339 or twitter dates, may be repeated, the timestamp must be inside the post's object [default: from --schema]")
+    )]
+    timestamp: Vec<selector::Selector>,
+
+    #[structopt(
+        long,
+        requires("buckets"),
+        help("instead of the counts, write the phrases that occur much more often in one of the last --trend-window buckets than in the buckets before it, ranked by z-score, with the bucket they spiked in")
+    )]
+    trends: bool,
+
+    #[structopt(
+        long,
+        default_value = "1",
+        help("number of most recent buckets to look for spikes in with --trends")
+    )]
+    trend_window: std::num::NonZeroUsize,
+
+    #[structopt(
+        long,
+        help("number of buckets before a spike it is compared with [default: all of them]")
+    )]
+    trend_baseline: Option<std::num::NonZeroUsize>,
+
+    #[structopt(
+        long,
+        default_value = "3",
+        help("only write phrases that spiked by at least this many standard deviations")
+    )]
+    min_z_score: f64,
+
+    #[structopt(
+        long,
+        default_value = "5",
+        help("only write phrases that occur at least this often in the bucket they spiked in")
+    )]
+    min_spike_count: u32,
+
+    #[structopt(
+        long,
+        parse(try_from_str = time::parse_arg),
+        help("only count posts made at or after this time, e.g. 2021-03-04 or 2021-03-04T05:06:07Z, posts without a timestamp are skipped")
+    )]
+    since: Option<i64>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = time::parse_arg),
+        help("only count posts made before this time, posts without a timestamp are skipped")
+    )]
+    until: Option<i64>,
+
+    #[structopt(
+        long,
+        help("only count posts of the user ids in this file, one per line, posts without a user id are skipped")
+    )]
+    users_from: Option<std::path::PathBuf>,
+
+    #[structopt(skip)]
+    allowed_users: Option<hashbrown::HashSet<users::User>>,
+
+    #[structopt(
+        long = "where",
+        number_of_values(1),
+        help("only count posts whose field has (==) or doesn't have (!=) a value, e.g. lang==en or user.verified!=true, may be repeated and all must hold, the field must be inside the post's object, or be a column name in csv/tsv files")
+    )]
+    predicates: Vec<selector::Predicate>,
+
+    #[structopt(
+        long,
+        possible_values(weight::Transform::NAMES),
+        conflicts_with("buckets"),
+        help("count each occurrence of a phrase with the score of its post (linear) or ln(1 + score) (log) instead of 1, the first column becomes the sum of the weights with 3 decimals of precision, posts without a score, or whose score is not a number, and negative scores weigh 0")
+    )]
+    weight: Option<weight::Transform>,
+
+    #[structopt(
+        long,
+        number_of_values(1),
+        help("path to the score of each post, e.g. score, may be repeated, the score must be inside the post's object [default: from --schema]")
+    )]
+    score: Vec<selector::Selector>,
+
+    #[structopt(
+        long,
+        help("count at most this many occurrences of each phrase per user, for json with a user scope, e.g. users[*] in the posts schema or --user ...[*], the cap holds within one such element, for csv/tsv per --user-column value within one file")
+    )]
+    per_user_cap: Option<std::num::NonZeroU32>,
+
+    #[structopt(
+        long,
+        help("skip posts whose text fields are exactly the same as those of a post that was already counted, e.g. reposts, in any file, a plain text file is one post unless --paragraphs is given")
+    )]
+    dedup: bool,
+
+    #[structopt(
+        long,
+        help("treat every input as newline delimited json, by default only .jsonl and .ndjson files and .json files or stdin that look like ndjson are")
+    )]
+    ndjson: bool,
+
+    #[structopt(
+        long,
+        number_of_values(1),
+        help("path to the text fields that should be counted, may be repeated, e.g. data.children[*].body [default: users[*].posts[*].{text,description}]")
+    )]
+    select: Vec<selector::Selector>,
+
+    #[structopt(
+        long = "schema",
+        possible_values(selector::Preset::NAMES),
+        conflicts_with("select"),
+        help("use the text fields of a known dump format [default: posts]")
+    )]
+    preset: Option<selector::Preset>,
+
+    #[structopt(
+        long,
+        help("count each blank line separated paragraph of plain text and markdown files as its own post")
+    )]
+    paragraphs: bool,
+
+    #[structopt(
+        long,
+        help("strip markdown syntax (links, emphasis, code blocks, ...) from .md files before counting")
+    )]
+    strip_markdown: bool,
+
+    #[structopt(
+        long,
+        number_of_values(1),
+        help("name or zero based index of a csv/tsv column that holds text, may be repeated [default: text]")
+    )]
+    text_column: Vec<delimited::Column>,
+
+    #[structopt(
+        long,
+        help("name or zero based index of the csv/tsv column that holds the user id")
+    )]
+    user_column: Option<delimited::Column>,
+
+    #[structopt(
+        long,
+        help("name or zero based index of the csv/tsv column that holds the timestamp")
+    )]
+    timestamp_column: Option<delimited::Column>,
+
+    #[structopt(
+        long,
+        help(
+            "name or zero based index of the csv/tsv column that holds the score used by --weight"
+        )
+    )]
+    score_column: Option<delimited::Column>,
+
+    #[structopt(
+        long,
+        default_value = "268435456",
+        help("files of at least this many bytes are parsed as they are read instead of being loaded into memory first, compressed files and stdin always are")
+    )]
+    stream_threshold: u64,
+
+    #[structopt(
+        long,
+        help("memory map uncompressed files instead of reading them, the files must not be modified while this runs")
+    )]
+    mmap: bool,
+
+    #[structopt(
+        long,
+        default_value = "skip",
+        possible_values(encoding::Policy::NAMES),
+        help("what to do with files that aren't valid utf-8: skip them, replace invalid bytes, or transcode them from --encoding (or a guessed encoding), streamed files are decoded from the first invalid byte on, guessing the encoding from the bytes around it")
+    )]
+    invalid_utf8: encoding::Policy,
+
+    #[structopt(
+        long,
+        parse(try_from_str = encoding::parse_encoding),
+        help("the encoding to transcode invalid utf-8 files from, e.g. latin1 or shift_jis")
+    )]
+    encoding: Option<&'static encoding_rs::Encoding>,
+
+    #[structopt(
+        long,
+        help("write every input that failed (or had to be decoded) and why to this file as a json array")
+    )]
+    error_report: Option<std::path::PathBuf>,
+
+    #[structopt(
+        long,
+        help("keep going when a temp file can't be written (its counts stay in memory) or read back (its counts are lost), and don't fail the run because some inputs failed")
+    )]
+    continue_on_error: bool,
+
+    #[structopt(skip)]
+    schema: selector::Node,
+
+    #[structopt(skip)]
+    filter: input::Filter,
+
     #[structopt(long, help("disable all logging"))]
     no_log: bool,
 
@@ -78,56 +378,209 @@ struct Config {
     cache_threshold: usize,
 }
 
-fn insert_value(phrase: Phrase, count: u32, phrase_counts: &mut Map) {
-    use std::hash::{BuildHasher, Hash, Hasher};
+fn insert_value(phrase: &str, post: &post::Post, phrase_counts: &mut Map) {
+    phrase_counts
+        .raw_entry_mut()
+        .from_key(phrase)
+        .or_insert_with(|| (phrase.into(), post::Counts::default()))
+        .1
+        .add(post);
+}
+
+fn merge(phrase_counts: &mut Map, other: Map) {
+    phrase_counts.reserve(other.len());
 
-    let mut hasher = phrase_counts.hasher().build_hasher();
-    phrase.hash(&mut hasher);
-    let hash = hasher.finish();
+    for (phrase, counts) in other {
+        phrase_counts.entry(phrase).or_default().merge(counts);
+    }
+}
 
-    *phrase_counts
-        .raw_entry_mut()
-        .from_hash(hash, |item| {
-            item.iter()
-                .map(AsRef::<str>::as_ref)
-                .eq(phrase.iter().copied())
-        })
-        .or_insert_with(|| (config::to_owned(phrase), 0))
-        .1 += count;
+fn bad_lines(count: usize) -> Result<(), Failure> {
+    if count == 0 {
+        Ok(())
+    } else {
+        Err(Failure::BadLines(count))
+    }
 }
 
+fn bad_line(file_path: &Path, line_number: usize, err: serde_json::Error) {
+    let failure = Failure::from_json(err);
+    warn!(
+        "BAD LINE {:?}:{} {} {}",
+        file_path,
+        line_number,
+        failure.reason(),
+        failure
+    );
+}
+
+fn process_line(config: &Config, line: &str, phrase_counts: &mut Map) -> serde_json::Result<()> {
+    let mut deserializer = serde_json::Deserializer::from_str(line);
+    proc_file::ProcFile::new(phrase_counts, config)
+        .deserialize(&mut deserializer)
+        .and_then(|()| deserializer.end())
+}
+
+fn process_lines(
+    config: &Config,
+    file_path: &Path,
+    file: &str,
+    phrase_counts: &mut Map,
+) -> Result<(), Failure> {
+    let mut bad_line_count = 0;
+
+    for (line_number, line) in file.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Err(err) = process_line(config, line, phrase_counts) {
+            bad_line_count += 1;
+            bad_line(file_path, line_number + 1, err);
+        }
+    }
+
+    bad_lines(bad_line_count)
+}
+
+fn stream_lines(
+    config: &Config,
+    file_path: &Path,
+    line: &mut String,
+    mut file: impl BufRead,
+    phrase_counts: &mut Map,
+) -> Result<(), Failure> {
+    let mut bad_line_count = 0;
+
+    for line_number in 1.. {
+        line.clear();
+
+        if file.read_line(line)? == 0 {
+            break;
+        }
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Err(err) = process_line(config, line, phrase_counts) {
+            bad_line_count += 1;
+            bad_line(file_path, line_number, err);
+        }
+    }
+
+    bad_lines(bad_line_count)
+}
+
+fn process_input(
+    start: Instant,
+    config: &Config,
+    file_contents: &mut String,
+    phrase_counts: &mut Map,
+    input: input::Input,
+) {
+    match input {
+        input::Input::File(file_path) => {
+            if config.mmap {
+                match input::map(&file_path) {
+                    Ok(Some(mapping)) => {
+                        let result =
+                            map_file(config, file_contents, phrase_counts, &file_path, &mapping);
+                        return report(start, &file_path, result);
+                    }
+                    // compressed files can't be parsed in place
+                    Ok(None) => (),
+                    Err(err) => return report(start, &file_path, Err(Failure::Open(err))),
+                }
+            }
+
+            match input::open_sized(&file_path) {
+                Ok((file, size)) => process_file(
+                    start,
+                    config,
+                    file_contents,
+                    phrase_counts,
+                    &file_path,
+                    file,
+                    size,
+                ),
+                Err(err) => report(start, &file_path, Err(Failure::Open(err))),
+            }
+        }
+        input::Input::Stdin => match input::stdin() {
+            Ok(file) => process_file(
+                start,
+                config,
+                file_contents,
+                phrase_counts,
+                Path::new(input::STDIN),
+                file,
+                None,
+            ),
+            Err(err) => report(start, Path::new(input::STDIN), Err(Failure::Open(err))),
+        },
+        input::Input::Archive(archive_path, kind) => {
+            let result = input::for_each_member(&archive_path, kind, |file_path, file, size| {
+                TOTAL_FILE_COUNT.fetch_add(1, Relaxed);
+                process_file(
+                    start,
+                    config,
+                    file_contents,
+                    phrase_counts,
+                    file_path,
+                    file,
+                    size,
+                )
+            });
+
+            if let Err(err) = result {
+                error!("CANNOT READ ARCHIVE {:?} {}", archive_path, err);
+                failure::record(&archive_path, &Failure::Read(err));
+            }
+        }
+    }
+}
+
+/// `size` is the size of the data in the file if it is known up front, files whose size
+/// isn't, e.g. compressed ones or stdin, are always streamed
 fn process_file(
     start: Instant,
+    config: &Config,
     file_contents: &mut String,
     phrase_counts: &mut Map,
-    file_path: impl AsRef<Path>,
+    file_path: &Path,
+    file: impl Read,
+    size: Option<u64>,
 ) {
-    let file_path = file_path.as_ref();
-    let mut file = match std::fs::File::open(file_path) {
-        Ok(file) => file,
-        Err(_) => {
-            let count = FILE_PROCESED_COUNT.fetch_add(1, Relaxed);
-            error!(
-                "CANNOT OPEN ({:4}/{:4}) {:?}",
-                count,
-                TOTAL_FILE_COUNT.load(Relaxed),
-                file_path
-            );
-            return;
+    let result = if size.is_none_or(|size| size >= config.stream_threshold) {
+        let file = BufReader::new(encoding::DecodeReader::new(config, file_path, file));
+
+        if config.invalid_utf8 == encoding::Policy::Skip {
+            // invalid utf-8 may only turn up after much of the file was counted, so it is
+            // counted on its own until then, like a file that is read into memory
+            let mut file_counts = Map::new();
+            let result = stream_file(config, file_contents, &mut file_counts, file_path, file);
+
+            if !matches!(result, Err(Failure::InvalidUtf8 { .. })) {
+                merge(phrase_counts, file_counts);
+            }
+
+            result
+        } else {
+            stream_file(config, file_contents, phrase_counts, file_path, file)
         }
+    } else {
+        read_file(config, file_contents, phrase_counts, file_path, file)
     };
 
-    file_contents.clear();
-    let size = file.read_to_string(file_contents).unwrap();
-    let file = &file_contents[..size];
+    report(start, file_path, result);
+}
 
-    let result = proc_file::ProcFile::new(phrase_counts)
-        .deserialize(&mut serde_json::Deserializer::from_str(file));
+fn report(start: Instant, file_path: &Path, result: Result<(), Failure>) {
+    let count = FILE_PROCESED_COUNT.fetch_add(1, Relaxed);
 
-    match result {
+    let failure = match result {
         Ok(()) => {
-            let count = FILE_PROCESED_COUNT.fetch_add(1, Relaxed);
-
             info!(
                 "FINISHED ({:4}/{:4}) {:.2} {:?}",
                 count,
@@ -135,21 +588,171 @@ fn process_file(
                 start.elapsed().as_secs_f32(),
                 file_path
             );
+            return;
         }
-        Err(_) => {
-            let count = FILE_PROCESED_COUNT.fetch_add(1, Relaxed);
-            warn!(
-                "EMPTY FILE ({:4}/{:4}) {:.2} {:?}",
-                count,
-                TOTAL_FILE_COUNT.load(Relaxed),
-                start.elapsed().as_secs_f32(),
-                file_path
-            );
+        Err(failure) => failure,
+    };
+
+    failure::record(file_path, &failure);
+
+    match failure {
+        Failure::BadLines(bad_lines) => warn!(
+            "FINISHED WITH {} BAD LINES ({:4}/{:4}) {:.2} {:?}",
+            bad_lines,
+            count,
+            TOTAL_FILE_COUNT.load(Relaxed),
+            start.elapsed().as_secs_f32(),
+            file_path
+        ),
+        Failure::Empty => warn!(
+            "EMPTY FILE ({:4}/{:4}) {:.2} {:?}",
+            count,
+            TOTAL_FILE_COUNT.load(Relaxed),
+            start.elapsed().as_secs_f32(),
+            file_path
+        ),
+        failure => error!(
+            "{} ({:4}/{:4}) {:.2} {:?} {}",
+            failure.label(),
+            count,
+            TOTAL_FILE_COUNT.load(Relaxed),
+            start.elapsed().as_secs_f32(),
+            file_path,
+            failure
+        ),
+    }
+}
+
+fn read_file(
+    config: &Config,
+    file_contents: &mut String,
+    phrase_counts: &mut Map,
+    file_path: &Path,
+    mut file: impl Read,
+) -> Result<(), Failure> {
+    let mut bytes = std::mem::take(file_contents).into_bytes();
+    bytes.clear();
+    file.read_to_end(&mut bytes)?;
+
+    *file_contents = match String::from_utf8(bytes) {
+        Ok(contents) => contents,
+        Err(err) => decode(config, file_path, err.as_bytes(), err.utf8_error())?,
+    };
+
+    process_contents(config, phrase_counts, file_path, file_contents)
+}
+
+fn map_file(
+    config: &Config,
+    file_contents: &mut String,
+    phrase_counts: &mut Map,
+    file_path: &Path,
+    file: &[u8],
+) -> Result<(), Failure> {
+    let file = match std::str::from_utf8(file) {
+        Ok(file) => file,
+        Err(err) => {
+            *file_contents = decode(config, file_path, file, err)?;
+            file_contents
+        }
+    };
+
+    process_contents(config, phrase_counts, file_path, file)
+}
+
+fn decode(
+    config: &Config,
+    file_path: &Path,
+    bytes: &[u8],
+    err: std::str::Utf8Error,
+) -> Result<String, Failure> {
+    let offset = err.valid_up_to() as u64;
+    let (contents, decoded) =
+        encoding::decode(config, bytes, err).map_err(|_| Failure::InvalidUtf8 { offset })?;
+    encoding::report(file_path, offset, &decoded);
+    Ok(contents)
+}
+
+fn process_contents(
+    config: &Config,
+    phrase_counts: &mut Map,
+    file_path: &Path,
+    file: &str,
+) -> Result<(), Failure> {
+    match input::Format::detect(file_path, config, Some(file)) {
+        input::Format::Json if file.trim().is_empty() => Err(Failure::Empty),
+        input::Format::Json => {
+            let mut deserializer = serde_json::Deserializer::from_str(file);
+
+            // anything after the document is an error, not silently dropped data
+            proc_file::ProcFile::new(phrase_counts, config)
+                .deserialize(&mut deserializer)
+                .and_then(|()| deserializer.end())
+                .map_err(Failure::from_json)
+        }
+        input::Format::Ndjson => process_lines(config, file_path, file, phrase_counts),
+        format @ (input::Format::Csv | input::Format::Tsv) => {
+            delimited::process_delimited(config, format, file_path, file.as_bytes(), phrase_counts)
+        }
+        format @ (input::Format::Text | input::Format::Markdown) => {
+            text::process_text(config, format, file, phrase_counts);
+            Ok(())
         }
     }
 }
 
-fn serialize_to_temp(temp_dir: &tempfile::TempDir, phrase_counts: Map) {
+/// like `read_file`, but only one post (or line, or paragraph) is held in memory at a time
+fn stream_file(
+    config: &Config,
+    buffer: &mut String,
+    phrase_counts: &mut Map,
+    file_path: &Path,
+    mut file: impl BufRead,
+) -> Result<(), Failure> {
+    // json and ndjson are told apart by the first line, so it is read whole before anything
+    // is parsed
+    let mut first_line = Vec::new();
+
+    while first_line.iter().all(u8::is_ascii_whitespace) {
+        let read = (&mut file)
+            .take(MAX_FIRST_LINE)
+            .read_until(b'\n', &mut first_line)?;
+
+        if read == 0 || !first_line.ends_with(b"\n") {
+            break;
+        }
+    }
+
+    // and whether there is more after it
+    let line_len = first_line.len();
+    first_line.extend_from_slice(file.fill_buf()?);
+    let format = input::Format::detect(file_path, config, Some(input::utf8_prefix(&first_line)));
+    first_line.truncate(line_len);
+
+    let mut file = std::io::Cursor::new(first_line).chain(file);
+
+    match format {
+        input::Format::Json if input::skip_whitespace(&mut file)? => Err(Failure::Empty),
+        input::Format::Json => {
+            let mut deserializer = serde_json::Deserializer::from_reader(file);
+
+            proc_file::ProcFile::new(phrase_counts, config)
+                .deserialize(&mut deserializer)
+                .and_then(|()| deserializer.end())
+                .map_err(Failure::from_json)
+        }
+        input::Format::Ndjson => stream_lines(config, file_path, buffer, file, phrase_counts),
+        format @ (input::Format::Csv | input::Format::Tsv) => {
+            delimited::process_delimited(config, format, file_path, file, phrase_counts)
+        }
+        format @ (input::Format::Text | input::Format::Markdown) => {
+            text::stream_text(config, format, buffer, file, phrase_counts)?;
+            Ok(())
+        }
+    }
+}
+
+fn serialize_to_temp(temp_dir: &tempfile::TempDir, phrase_counts: &Map) -> Result<(), Error> {
     info!("start save: {}", phrase_counts.len());
 
     let file_id = TEMP_FILE_COUNT.fetch_add(1, Relaxed);
@@ -159,28 +762,338 @@ fn serialize_to_temp(temp_dir: &tempfile::TempDir, phrase_counts: Map) {
         .read(false)
         .create_new(true)
         .open(&file_path)
-        .unwrap();
+        .map_err(|err| Error::io("create temp file", &file_path, err))?;
 
-    let file = BufWriter::new(&file);
+    let mut file = BufWriter::new(&file);
     let now = Instant::now();
 
     let a_len = phrase_counts.len();
 
-    bincode::config::DefaultOptions::default()
+    let result = bincode::config::DefaultOptions::default()
         .with_no_limit()
-        .serialize_into(file, &phrase_counts)
-        .unwrap();
+        .serialize_into(&mut file, phrase_counts)
+        .map_err(|err| error::from_bincode(*err))
+        .and_then(|()| file.flush());
+
+    if let Err(err) = result {
+        // a partial temp file can't be read back
+        drop(file);
+        let _ = std::fs::remove_file(&file_path);
+        return Err(Error::io("write temp file", file_path, err));
+    }
 
     info!(
         "finish save: {} ({} ms)",
         a_len,
         now.elapsed().as_secs_f32() * 1000.0
     );
+
+    Ok(())
 }
 
-fn main() {
-    let mut config: Config = Config::from_args();
+fn spill(config: &Config, temp_dir: &tempfile::TempDir, phrase_counts: Map) {
+    let err = match serialize_to_temp(temp_dir, &phrase_counts) {
+        Ok(()) => return,
+        Err(err) => err,
+    };
+
+    if config.continue_on_error {
+        error!("{}, keeping {} phrases in memory", err, phrase_counts.len());
+        UNSPILLED
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(phrase_counts);
+    } else {
+        error!("{}, stopping", err);
+        error::abort(err);
+    }
+}
+
+fn read_temp(
+    file_path: &Path,
+    file_contents: &mut Vec<u8>,
+    phrase_counts: &mut map::Map,
+) -> std::io::Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .write(false)
+        .read(true)
+        .open(file_path)?;
+    let len = file.metadata()?.len();
+
+    file_contents.clear();
+    file_contents.resize(len as usize, 0);
+    BufReader::new(file).read_exact(file_contents)?;
+
+    bincode::config::DefaultOptions::default()
+        .with_no_limit()
+        .deserialize_seed(des_collect::DesCollect(phrase_counts), file_contents)
+        .map_err(|err| error::from_bincode(*err))
+}
+
+/// phrases grouped by their count (or weight), and their post and user counts if requested
+type Table = BTreeMap<Reverse<(weight::Weight, Option<u32>, Option<u64>)>, Vec<PhraseBuf>>;
+
+fn group(config: &Config, phrases: Vec<(PhraseBuf, post::Counts)>) -> Table {
+    let mut table = Table::new();
+
+    for (phrase, counts) in phrases {
+        let posts = Some(counts.posts()).filter(|_| config.document_frequency);
+        let users = config.distinct_users.map(|_| counts.user_count());
+
+        table
+            .entry(Reverse((counts.total(config), posts, users)))
+            .or_default()
+            .push(phrase);
+    }
+
+    table
+}
+
+fn write_output(path: &Path, table: Table) -> std::io::Result<()> {
+    let table_len = table.len();
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .read(false)
+        .open(path)?;
+    let mut file = BufWriter::new(file);
+    let file = &mut file;
+
+    for (i, (Reverse((count, posts, users)), words)) in table.into_iter().enumerate() {
+        let i = i + 1;
+        write!(file, "{}", count)?;
+
+        if let Some(posts) = posts {
+            write!(file, "\t{}", posts)?;
+        }
+
+        if let Some(users) = users {
+            write!(file, "\t{}", users)?;
+        }
+
+        write!(file, "\t{}", words.len())?;
+        info!("prepare to emit: {}/{} - {} ", i, table_len, words.len());
+
+        if words.len() < 1_000_000 {
+            for phrase in words {
+                write!(file, "\t{}", phrase)?;
+            }
+        }
+
+        writeln!(file)?;
+        info!("writen: {}/{}", i, table_len);
+    }
+
+    file.flush()
+}
+
+/// a header row with a column per bucket, then a row per phrase, most common first
+fn write_time_series(
+    config: &Config,
+    bucket: time::Bucket,
+    path: &Path,
+    mut phrases: Vec<(PhraseBuf, post::Counts)>,
+) -> std::io::Result<()> {
+    phrases.sort_unstable_by(|(a, a_counts), (b, b_counts)| {
+        b_counts.count.cmp(&a_counts.count).then_with(|| a.cmp(b))
+    });
+
+    let buckets = phrases
+        .iter()
+        .flat_map(|(_, counts)| {
+            counts
+                .buckets()
+                .into_iter()
+                .flat_map(|buckets| buckets.keys())
+        })
+        .copied()
+        .collect::<std::collections::BTreeSet<_>>();
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .read(false)
+        .open(path)?;
+    let mut file = BufWriter::new(file);
+    let file = &mut file;
+
+    write!(file, "phrase\tcount")?;
+
+    if config.document_frequency {
+        write!(file, "\tposts")?;
+    }
+
+    if config.distinct_users.is_some() {
+        write!(file, "\tusers")?;
+    }
+
+    for &start in &buckets {
+        write!(file, "\t{}", bucket.label(start))?;
+    }
+
+    writeln!(file)?;
+
+    info!(
+        "prepare to emit: {} phrases, {} buckets",
+        phrases.len(),
+        buckets.len()
+    );
+
+    for (phrase, counts) in phrases {
+        write!(file, "{}\t{}", phrase, counts.count)?;
+
+        if config.document_frequency {
+            write!(file, "\t{}", counts.posts())?;
+        }
+
+        if config.distinct_users.is_some() {
+            write!(file, "\t{}", counts.user_count())?;
+        }
+
+        for start in &buckets {
+            let count = counts
+                .buckets()
+                .and_then(|buckets| buckets.get(start))
+                .copied()
+                .unwrap_or(0);
+
+            write!(file, "\t{}", count)?;
+        }
+
+        writeln!(file)?;
+    }
+
+    file.flush()
+}
+
+/// `out.txt` becomes `out.2.txt` for bigrams
+fn order_output(output: &Path, order: usize) -> PathBuf {
+    let mut name = output.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", order));
+
+    if let Some(extension) = output.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+
+    output.with_file_name(name)
+}
+
+/// build the schema from the selectors and check that the options fit together
+fn prepare(config: &mut Config) -> Result<(), structopt::clap::Error> {
+    use structopt::clap::{Error, ErrorKind};
+
+    config.schema = if config.select.is_empty() {
+        selector::Node::from_preset(config.preset.unwrap_or_default())
+    } else {
+        selector::Node::new(&config.select)
+    };
+
+    // user ids, timestamps and scores are only looked at if they are used, so they can't cause
+    // shape errors otherwise
+    if config.distinct_users.is_some()
+        || config.users_from.is_some()
+        || config.per_user_cap.is_some()
+    {
+        if !config.user.is_empty() {
+            config.schema.add_users(&config.user);
+        } else if config.select.is_empty() {
+            config
+                .schema
+                .add_preset_users(config.preset.unwrap_or_default());
+        }
+    }
+
+    if config.buckets.is_some() || config.since.is_some() || config.until.is_some() {
+        if !config.timestamp.is_empty() {
+            config.schema.add_timestamps(&config.timestamp);
+        } else if config.select.is_empty() {
+            config
+                .schema
+                .add_preset_timestamps(config.preset.unwrap_or_default());
+        }
+    }
+
+    if config.weight.is_some() {
+        if !config.score.is_empty() {
+            config.schema.add_scores(&config.score);
+        } else if config.select.is_empty() {
+            config
+                .schema
+                .add_preset_scores(config.preset.unwrap_or_default());
+        }
+    }
 
+    if config.per_user_cap.is_some()
+        && !config.schema.has_user_scope()
+        && config.user_column.is_none()
+    {
+        return Err(Error::with_description(
+            "--per-user-cap needs a user scope, e.g. --user users[*], --schema posts or --user-column",
+            ErrorKind::ArgumentConflict,
+        ));
+    }
+
+    // which predicates a post matched is tracked in the bits of a u64
+    if config.predicates.len() > 64 {
+        return Err(Error::with_description(
+            "at most 64 --where filters are supported",
+            ErrorKind::TooManyValues,
+        ));
+    }
+
+    config.schema.add_predicates(&config.predicates);
+
+    // anonymous users are never in the list, so every post would be filtered out
+    if config.users_from.is_some() && !config.schema.has_user_id() && config.user_column.is_none() {
+        return Err(Error::with_description(
+            "--users-from needs user ids, e.g. --user users[*].id, --schema reddit or --user-column",
+            ErrorKind::ArgumentConflict,
+        ));
+    }
+
+    // posts without a timestamp are never in the range
+    if (config.since.is_some() || config.until.is_some())
+        && !config.schema.has_timestamp()
+        && config.timestamp_column.is_none()
+    {
+        return Err(Error::with_description(
+            "--since and --until need timestamps, e.g. --timestamp created_utc, --schema reddit or --timestamp-column",
+            ErrorKind::ArgumentConflict,
+        ));
+    }
+
+    // no post would have a bucket, so the table would have no bucket columns
+    if config.buckets.is_some()
+        && !config.schema.has_timestamp()
+        && config.timestamp_column.is_none()
+    {
+        return Err(Error::with_description(
+            "--buckets needs timestamps, e.g. --timestamp created_utc, --schema reddit or --timestamp-column",
+            ErrorKind::ArgumentConflict,
+        ));
+    }
+
+    // every post would weigh nothing
+    if config.weight.is_some() && !config.schema.has_score() && config.score_column.is_none() {
+        return Err(Error::with_description(
+            "--weight needs scores, e.g. --score score, --schema reddit or --score-column",
+            ErrorKind::ArgumentConflict,
+        ));
+    }
+
+    config.filter = input::Filter::new(&config.include, &config.exclude, config.skip_hidden)
+        .map_err(|err| {
+            Error::with_description(&format!("invalid glob: {}", err), ErrorKind::InvalidValue)
+        })?;
+
+    Ok(())
+}
+
+fn run(mut config: Config) -> Result<(), Error> {
     if !config.no_log {
         stderrlog::new()
             .module(module_path!())
@@ -188,15 +1101,28 @@ fn main() {
             .color(stderrlog::ColorChoice::Auto)
             .verbosity(if config.verbose { 4 } else { 1 })
             .init()
-            .unwrap();
+            .map_err(|err| Error::Setup(format!("cannot set up logging: {}", err)))?;
     }
 
     let start = Instant::now();
 
     let paths = std::mem::take(&mut config.folders);
+
+    prepare(&mut config).unwrap_or_else(|err| err.exit());
+
+    if let Some(path) = &config.users_from {
+        let users = users::read_list(path).map_err(|source| Error::Input {
+            path: path.clone(),
+            source,
+        })?;
+        info!("filter on {} users", users.len());
+        config.allowed_users = Some(users);
+    }
+
     let config = config;
 
-    let temp_dir = tempfile::tempdir().unwrap();
+    let temp_dir = tempfile::tempdir()
+        .map_err(|err| Error::io("create temp folder in", std::env::temp_dir(), err))?;
     let temp_dir = &temp_dir;
     let save_pool = rayon::ThreadPoolBuilder::new()
         .num_threads(config.background_threads.unwrap_or_else(|| {
@@ -207,46 +1133,49 @@ fn main() {
             }
         }))
         .build()
-        .unwrap();
+        .map_err(|err| Error::Setup(format!("cannot start background threads: {}", err)))?;
 
     rayon::ThreadPoolBuilder::new()
         .num_threads(config.worker_threads.unwrap_or_else(num_cpus::get))
         .build_global()
-        .unwrap();
+        .map_err(|err| Error::Setup(format!("cannot start worker threads: {}", err)))?;
 
-    let files: Vec<_> = paths
+    let mut files: Vec<_> = paths
         .into_iter()
         .flat_map(|path| {
-            let walk_dir = WalkDir::new(path).into_iter();
-            walk_dir.filter_entry(|dir_entry| {
-                if dir_entry.file_type().is_dir() {
-                    return true;
-                }
-
-                if !dir_entry.file_type().is_file() {
-                    return false;
-                }
-
-                let path = dir_entry.path();
+            if path == Path::new(input::STDIN) {
+                return Either::Left(std::iter::once(input::Input::Stdin));
+            }
 
-                path.extension()
-                    .map_or(false, |ext| ext.to_str() == Some("json"))
-            })
+            Either::Right(input::walk(path, &config))
         })
-        .flatten()
-        .filter(|dir_entry| dir_entry.file_type().is_file())
-        .map(|dir_entry| dir_entry.path().to_owned())
         .collect();
 
-    TOTAL_FILE_COUNT.store(files.len(), Relaxed);
+    if let Some(manifest) = &config.files_from {
+        let paths = input::read_manifest(manifest).map_err(|source| Error::Input {
+            path: manifest.clone(),
+            source,
+        })?;
+        files.extend(paths.into_iter().map(input::Input::from_manifest));
+    }
+
+    // archive members are counted as they are found
+    TOTAL_FILE_COUNT.store(
+        files.iter().filter(|input| !input.is_archive()).count(),
+        Relaxed,
+    );
+
+    let config = &config;
 
     let words = save_pool.scope(|save_pool| {
         files
             .into_par_iter()
             .fold_with(
                 (String::new(), HashMap::new()),
-                |(mut file_contents, mut phrase_counts), file_path| {
-                    process_file(start, &mut file_contents, &mut phrase_counts, file_path);
+                |(mut file_contents, mut phrase_counts), input| {
+                    if !error::is_aborted() {
+                        process_input(start, config, &mut file_contents, &mut phrase_counts, input);
+                    }
                     (file_contents, phrase_counts)
                 },
             )
@@ -255,7 +1184,7 @@ fn main() {
                 let now = Instant::now();
 
                 let mut maps = [&mut a, &mut b];
-                let maps = if config.no_cache {
+                let maps = if config.no_cache || error::is_aborted() {
                     &mut [][..]
                 } else {
                     &mut maps[..]
@@ -264,9 +1193,7 @@ fn main() {
                 for a in maps.iter_mut() {
                     if a.len() > config.cache_threshold {
                         let phrase_counts = std::mem::take(&mut **a);
-                        save_pool.spawn(move |_| {
-                            serialize_to_temp(&temp_dir, phrase_counts);
-                        });
+                        save_pool.spawn(move |_| spill(config, temp_dir, phrase_counts));
                     }
                 }
 
@@ -274,11 +1201,7 @@ fn main() {
                     std::mem::swap(&mut a, &mut b);
                 }
 
-                a.reserve(b.len());
-
-                for (b, v) in b {
-                    *a.entry(b).or_default() += v;
-                }
+                merge(&mut a, b);
 
                 info!("reduce: {} ms", now.elapsed().as_secs_f32() * 1000.0);
 
@@ -286,11 +1209,65 @@ fn main() {
             })
     });
 
+    drop(save_pool);
+
+    // the inputs that failed before a spill aborted the run are still reported
+    if let Some(error_report) = &config.error_report {
+        if let Err(err) = failure::write_report(error_report) {
+            error!("unable to write error report {:?}: {}", error_report, err);
+        }
+    }
+
+    if let Some(err) = error::take() {
+        return Err(err);
+    }
+
     info!("data collection: {} s", start.elapsed().as_secs_f32());
 
-    drop(save_pool);
+    let invalid_timestamps = post::INVALID_TIMESTAMP_COUNT.load(Relaxed);
+
+    if invalid_timestamps != 0 {
+        warn!(
+            "skipped {} posts with a timestamp that couldn't be parsed or is out of range",
+            invalid_timestamps
+        );
+    }
+
+    let late_user_ids = proc_file::LATE_USER_ID_COUNT.load(Relaxed);
+
+    if late_user_ids != 0 {
+        warn!(
+            "{} user ids came after some of their user's posts, those posts were counted as an anonymous user",
+            late_user_ids
+        );
+    }
+
+    let filtered = post::FILTERED_POST_COUNT.load(Relaxed);
+
+    if filtered != 0 {
+        info!("filtered out: {} posts", filtered);
+    }
+
+    let duplicates = dedup::DUPLICATE_POST_COUNT.load(Relaxed);
+
+    if duplicates != 0 {
+        warn!("duplicates dropped: {} posts", duplicates);
+    }
+
+    let invalid_utf8 = [
+        ("skipped", &encoding::SKIPPED_FILE_COUNT),
+        ("lossy", &encoding::LOSSY_FILE_COUNT),
+        ("transcoded", &encoding::TRANSCODED_FILE_COUNT),
+    ];
+
+    for (outcome, count) in invalid_utf8.iter() {
+        let count = count.load(Relaxed);
+
+        if count != 0 {
+            warn!("invalid utf-8 files {}: {}", outcome, count);
+        }
+    }
 
-    let deser = bincode::config::DefaultOptions::default().with_no_limit();
     let mut file_contents = Vec::new();
 
     let temp_file_count = TEMP_FILE_COUNT.load(Relaxed);
@@ -299,84 +1276,226 @@ fn main() {
         .into_iter()
         .enumerate()
         .flat_map(|(i, x)| x.map(|x| (i, x)))
-        .filter(|(_, file)| file.file_type().is_file())
-        .filter_map(|(i, file)| {
-            let file_path = file.path();
-            match std::fs::OpenOptions::new()
-                .write(false)
-                .read(true)
-                .open(file_path)
-            {
-                Ok(file) => {
-                    info!("read temp: ({}/{}) {:?}", i, temp_file_count, file_path);
-                    Some(file)
-                }
-                Err(_) => {
-                    warn!(
-                        "unable to read: ({}/{}) {:?}",
-                        i, temp_file_count, file_path
-                    );
-                    None
-                }
-            }
-        });
+        .filter(|(_, file)| file.file_type().is_file());
 
     let mut map = map::Map::new();
 
-    for (phrase, count) in words {
-        map.add_owned(phrase, count);
+    for (phrase, counts) in words {
+        map.add_owned(phrase, counts);
+    }
+
+    let unspilled = std::mem::take(
+        &mut *UNSPILLED
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+    );
+
+    for words in unspilled {
+        for (phrase, counts) in words {
+            map.add_owned(phrase, counts);
+        }
     }
 
     let mut words = map;
+    let mut lost_temp_files = 0;
 
-    for file in temp_files {
-        let len = file.metadata().unwrap().len();
+    for (i, file) in temp_files {
+        let file_path = file.path();
+        info!("read temp: ({}/{}) {:?}", i, temp_file_count, file_path);
 
-        file_contents.clear();
-        file_contents.resize(len as usize, 0);
-        BufReader::new(file).read_exact(&mut file_contents).unwrap();
+        if let Err(err) = read_temp(file_path, &mut file_contents, &mut words) {
+            let err = Error::io("read temp file", file_path, err);
 
-        deser
-            .deserialize_seed(des_collect::DesCollect(&mut words), &file_contents)
-            .unwrap();
+            if !config.continue_on_error {
+                return Err(err);
+            }
+
+            error!("{}, its counts are lost", err);
+            lost_temp_files += 1;
+        }
     }
 
-    let mut table = BTreeMap::new();
+    // phrases of every length share the maps, so they are only split up by order here
+    let mut orders = BTreeMap::new();
 
-    for (word, count) in words {
-        table
-            .entry(Reverse(count))
+    for (phrase, counts) in words {
+        orders
+            .entry(phrase.split(' ').count())
             .or_insert_with(Vec::new)
-            .push(word);
+            .push((phrase, counts));
     }
 
-    let table_len = table.len();
+    let outputs = if config.all_orders {
+        (1..=config.n.get())
+            .map(|order| (order, order_output(&config.output, order)))
+            .collect()
+    } else {
+        vec![(config.n.get(), config.output.clone())]
+    };
 
-    let file = std::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .read(false)
-        .open(config.output)
-        .unwrap();
-    let mut file = BufWriter::new(file);
-    let file = &mut file;
+    for (order, output) in outputs {
+        let phrases = orders.remove(&order).unwrap_or_default();
 
-    #[allow(unused_must_use)]
-    for (i, (Reverse(key), words)) in table.into_iter().enumerate() {
-        let i = i + 1;
-        write!(file, "{}\t{}", key, words.len());
-        info!("prepare to emit: {}/{} - {} ", i, table_len, words.len());
+        let result = match config.buckets {
+            Some(bucket) if config.trends => trends::write_trends(config, bucket, &output, phrases),
+            Some(bucket) => write_time_series(config, bucket, &output, phrases),
+            None => write_output(&output, group(config, phrases)),
+        };
 
-        if words.len() < 1_000_000 {
-            for chunk in words {
-                config::print_result(&mut *file, chunk);
+        result.map_err(|err| Error::io("write output", &output, err))?;
+    }
+
+    info!("total time: {}", start.elapsed().as_secs_f32());
+
+    if lost_temp_files != 0 {
+        return Err(Error::Incomplete {
+            temp_files: lost_temp_files,
+        });
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let config: Config = Config::from_args();
+    let continue_on_error = config.continue_on_error;
+    let no_log = config.no_log;
+
+    let code = match run(config) {
+        Ok(()) if continue_on_error => 0,
+        Ok(()) => match failure::FAILED_INPUT_COUNT.load(Relaxed) {
+            0 => 0,
+            failed => {
+                warn!("{} inputs failed", failed);
+                error::EXIT_INPUT
+            }
+        },
+        Err(err) => {
+            if log::log_enabled!(log::Level::Error) {
+                error!("{}", err);
+            } else if !no_log {
+                eprintln!("error: {}", err);
             }
+
+            err.exit_code()
         }
+    };
 
-        writeln!(file);
-        info!("writen: {}/{}", i, table_len);
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a config with these arguments, set up like `run` does
+    pub fn config(args: &[&str]) -> Config {
+        try_config(args).unwrap()
     }
 
-    info!("total time: {}", start.elapsed().as_secs_f32());
+    pub fn counts<'a>(phrase_counts: &'a Map, phrase: &str) -> Option<&'a post::Counts> {
+        phrase_counts.get(phrase)
+    }
+
+    fn try_config(args: &[&str]) -> Result<Config, structopt::clap::Error> {
+        let mut config =
+            Config::from_iter(std::iter::once("search_posts").chain(args.iter().copied()));
+        prepare(&mut config)?;
+        Ok(config)
+    }
+
+    #[test]
+    fn buckets_need_timestamps() {
+        assert!(try_config(&["--buckets", "day"]).is_err());
+        assert!(try_config(&["--buckets", "day", "--select", "body"]).is_err());
+        assert!(try_config(&["--buckets", "week", "--schema", "twitter"]).is_ok());
+        assert!(try_config(&["--buckets", "month", "--timestamp-column", "at"]).is_ok());
+    }
+
+    #[test]
+    fn weight_needs_scores() {
+        assert!(try_config(&["--weight", "linear"]).is_err());
+        assert!(try_config(&["--weight", "log", "--select", "body"]).is_err());
+        assert!(try_config(&["--weight", "log", "--schema", "reddit"]).is_ok());
+        assert!(try_config(&["--weight", "linear", "--score", "users[*].posts[*].likes"]).is_ok());
+        assert!(try_config(&["--weight", "linear", "--score-column", "likes"]).is_ok());
+    }
+
+    #[test]
+    fn users_from_needs_user_ids() {
+        assert!(try_config(&["--users-from", "users.txt"]).is_err());
+        assert!(try_config(&["--users-from", "users.txt", "--user", "users[*].id"]).is_ok());
+        assert!(try_config(&["--users-from", "users.txt", "--schema", "twitter"]).is_ok());
+        assert!(try_config(&["--users-from", "users.txt", "--user-column", "user"]).is_ok());
+    }
+
+    #[test]
+    fn a_range_needs_timestamps() {
+        assert!(try_config(&["--since", "2021-05-03"]).is_err());
+        assert!(try_config(&["--until", "2021-05-03", "--select", "body"]).is_err());
+        assert!(try_config(&["--since", "2021-05-03", "--schema", "reddit"]).is_ok());
+        assert!(try_config(&[
+            "--since",
+            "2021-05-03",
+            "--timestamp",
+            "users[*].posts[*].at"
+        ])
+        .is_ok());
+        assert!(try_config(&["--until", "2021-05-03", "--timestamp-column", "at"]).is_ok());
+    }
+
+    fn count(args: &[&str], file_path: &str, file: &[u8]) -> Map {
+        let config = config(args);
+        let mut phrase_counts = Map::new();
+
+        process_file(
+            Instant::now(),
+            &config,
+            &mut String::new(),
+            &mut phrase_counts,
+            Path::new(file_path),
+            file,
+            Some(file.len() as u64),
+        );
+
+        phrase_counts
+    }
+
+    #[test]
+    fn invalid_utf8_skips_the_whole_streamed_file() {
+        let mut ndjson = "{\"body\": \"good post\"}\n".repeat(10_000).into_bytes();
+        ndjson.extend_from_slice(b"{\"body\": \"caf\xe9\"}\n");
+
+        for threshold in ["1", "1000000000"] {
+            let args = ["--schema", "reddit", "--stream-threshold", threshold];
+            assert!(count(&args, "a.json", &ndjson).is_empty());
+
+            let args = [&args[..], &["--invalid-utf8", "lossy"]].concat();
+            assert_eq!(
+                counts(&count(&args, "a.json", &ndjson), "good")
+                    .unwrap()
+                    .count,
+                10_000
+            );
+        }
+    }
+
+    #[test]
+    fn ndjson_with_a_long_first_line() {
+        let long = "word ".repeat(3_000);
+        let ndjson = format!(
+            "\n{{\"body\": \"{} first\"}}\n{{\"body\": \"second\"}}\n",
+            long
+        );
+
+        for threshold in ["1", "1000000000"] {
+            let args = ["--schema", "reddit", "--stream-threshold", threshold];
+            let phrase_counts = count(&args, "a.json", ndjson.as_bytes());
+
+            assert_eq!(counts(&phrase_counts, "first").unwrap().count, 1);
+            assert_eq!(counts(&phrase_counts, "second").unwrap().count, 1);
+        }
+    }
 }
@@ -3,13 +3,15 @@ use hashbrown::{hash_map::RawEntryMut, HashMap};
 use std::iter::Flatten;
 use std::vec::IntoIter;
 
-use std::hash::{BuildHasher, Hash, Hasher};
+use std::hash::{BuildHasher, Hash};
+
+use crate::post::Counts;
 
 #[derive(Clone, Copy)]
 pub struct NoHash;
 
 pub struct Map {
-    inner: Box<[HashMap<crate::PhraseBuf, u32, NoHash>]>,
+    inner: Box<[HashMap<crate::PhraseBuf, Counts, NoHash>]>,
     hasher: RandomState,
 }
 
@@ -21,33 +23,28 @@ impl Map {
         }
     }
 
-    fn hash<H: Hash>(hasher: &RandomState, value: &H) -> (u64, usize) {
-        let mut hasher = hasher.build_hasher();
-        value.hash(&mut hasher);
-        let hash = hasher.finish();
+    fn hash<H: Hash + ?Sized>(hasher: &RandomState, value: &H) -> (u64, usize) {
+        let hash = hasher.hash_one(value);
         let index = (hash & (64 - 1)) as usize;
         (hash, index)
     }
 
-    pub fn add(&mut self, phrase: crate::Phrase, value: u32) {
+    pub fn add(&mut self, phrase: &str, value: Counts) {
         let hasher = &self.hasher;
-        let (hash, index) = Self::hash(hasher, &phrase);
+        let (hash, index) = Self::hash(hasher, phrase);
 
-        match self.inner[index].raw_entry_mut().from_hash(hash, |key| {
-            key.iter()
-                .map(AsRef::<str>::as_ref)
-                .eq(phrase.iter().copied())
-        }) {
-            RawEntryMut::Occupied(entry) => *entry.into_mut() += value,
+        match self.inner[index]
+            .raw_entry_mut()
+            .from_hash(hash, |key| **key == *phrase)
+        {
+            RawEntryMut::Occupied(entry) => entry.into_mut().merge(value),
             RawEntryMut::Vacant(entry) => {
-                entry.insert_with_hasher(hash, crate::config::to_owned(phrase), value, |x| {
-                    Self::hash(hasher, x).0
-                });
+                entry.insert_with_hasher(hash, phrase.into(), value, |x| Self::hash(hasher, x).0);
             }
         }
     }
 
-    pub fn add_owned(&mut self, phrase: crate::PhraseBuf, value: u32) {
+    pub fn add_owned(&mut self, phrase: crate::PhraseBuf, value: Counts) {
         let hasher = &self.hasher;
         let (hash, index) = Self::hash(hasher, &phrase);
 
@@ -55,7 +52,7 @@ impl Map {
             .raw_entry_mut()
             .from_hash(hash, |key| *key == phrase)
         {
-            RawEntryMut::Occupied(entry) => *entry.into_mut() += value,
+            RawEntryMut::Occupied(entry) => entry.into_mut().merge(value),
             RawEntryMut::Vacant(entry) => {
                 entry.insert_with_hasher(hash, phrase, value, |x| Self::hash(hasher, x).0);
             }
@@ -64,8 +61,8 @@ impl Map {
 }
 
 impl IntoIterator for Map {
-    type IntoIter = Flatten<IntoIter<HashMap<crate::PhraseBuf, u32, NoHash>>>;
-    type Item = (crate::PhraseBuf, u32);
+    type IntoIter = Flatten<IntoIter<HashMap<crate::PhraseBuf, Counts, NoHash>>>;
+    type Item = (crate::PhraseBuf, Counts);
 
     fn into_iter(self) -> Self::IntoIter {
         self.inner.into_vec().into_iter().flatten()
@@ -0,0 +1,233 @@
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+use crate::users::{Mode, User, Users};
+use crate::weight::Weight;
+use crate::Config;
+
+// 0 is never handed out, so that it can mark phrases that weren't seen in any post yet
+static POST_COUNT: AtomicU64 = AtomicU64::new(1);
+pub static FILTERED_POST_COUNT: AtomicU64 = AtomicU64::new(0);
+pub static INVALID_TIMESTAMP_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// one unit of text for document frequency: the innermost json object that holds
+/// selected text fields, a csv row, or a plain text file (or paragraph)
+pub struct Post {
+    id: u64,
+    /// only set with --distinct-users
+    user: Option<(User, Mode)>,
+    /// the start of the time bucket of the post, only set with --buckets
+    bucket: Option<i64>,
+    /// what each occurrence of a phrase in the post adds, only set with --weight
+    weight: Weight,
+    /// if anything besides the count is counted for the post's phrases
+    extra: bool,
+}
+
+/// what is known about a post besides its text
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Meta {
+    pub user: Option<User>,
+    /// in seconds since the epoch
+    pub timestamp: Option<i64>,
+    pub score: Option<f64>,
+    /// a timestamp field of the post couldn't be parsed
+    pub invalid_timestamp: bool,
+    /// a bit for every --where predicate whose field had the predicate's value
+    pub matched: u64,
+}
+
+impl Meta {
+    /// values found later in the post win
+    pub fn merge(&mut self, other: Meta) {
+        self.user = other.user.or(self.user);
+        self.timestamp = other.timestamp.or(self.timestamp);
+        self.score = other.score.or(self.score);
+        self.invalid_timestamp |= other.invalid_timestamp;
+        self.matched |= other.matched;
+    }
+}
+
+impl Post {
+    /// `None` if the post is filtered out, so that it is never tokenized
+    pub fn new(config: &Config, meta: &Meta) -> Option<Self> {
+        let bucket = match (config.buckets, meta.timestamp) {
+            (Some(bucket), Some(timestamp)) => bucket.of(timestamp),
+            _ => None,
+        };
+
+        // it can't be told which bucket or range the post belongs to, a timestamp can also
+        // parse but be too far out to have a bucket
+        if meta.invalid_timestamp && meta.timestamp.is_none()
+            || config.buckets.is_some() && meta.timestamp.is_some() && bucket.is_none()
+        {
+            INVALID_TIMESTAMP_COUNT.fetch_add(1, Relaxed);
+            return None;
+        }
+
+        if !keep(config, meta) {
+            FILTERED_POST_COUNT.fetch_add(1, Relaxed);
+            return None;
+        }
+
+        Some(Self {
+            id: POST_COUNT.fetch_add(1, Relaxed),
+            user: config
+                .distinct_users
+                .and_then(|mode| Some((meta.user?, mode))),
+            bucket,
+            weight: config
+                .weight
+                .map_or_else(Weight::default, |transform| transform.weight(meta.score)),
+            extra: config.weight.is_some()
+                || config.document_frequency
+                || config.distinct_users.is_some()
+                || config.buckets.is_some(),
+        })
+    }
+}
+
+/// posts without a timestamp or user are dropped when those are filtered on
+fn keep(config: &Config, meta: &Meta) -> bool {
+    let in_range = match (config.since, config.until) {
+        (None, None) => true,
+        (since, until) => meta.timestamp.is_some_and(|timestamp| {
+            since.is_none_or(|since| timestamp >= since)
+                && until.is_none_or(|until| timestamp < until)
+        }),
+    };
+
+    let allowed_user = match &config.allowed_users {
+        Some(users) => meta.user.is_some_and(|user| users.contains(&user)),
+        None => true,
+    };
+
+    let predicates = config
+        .predicates
+        .iter()
+        .enumerate()
+        .all(|(i, predicate)| predicate.is_equal() == (meta.matched & (1 << i) != 0));
+
+    in_range && allowed_user && predicates
+}
+
+/// everything counted for a single phrase
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Counts {
+    /// every occurrence
+    pub count: u32,
+    /// boxed and only allocated when a post has something besides the count to add, so that
+    /// default runs keep small entries in memory and in spills
+    extra: Option<Box<Extra>>,
+}
+
+/// what only --weight, --document-frequency, --distinct-users or --buckets count
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Extra {
+    /// the summed weights of every occurrence, only counted with --weight
+    weight: Weight,
+    /// distinct posts that contain the phrase
+    posts: u32,
+    /// distinct users of the phrase
+    users: Option<Users>,
+    /// occurrences per time bucket, keyed by the start of the bucket
+    buckets: Option<HashMap<i64, u32>>,
+    /// the last post counted in `posts`, only meaningful while a map is being filled
+    #[serde(skip)]
+    last_post: u64,
+}
+
+impl Counts {
+    pub fn add(&mut self, post: &Post) {
+        self.count += 1;
+
+        if !post.extra {
+            return;
+        }
+
+        let extra = self.extra.get_or_insert_with(Default::default);
+        extra.weight += post.weight;
+
+        if let Some(bucket) = post.bucket {
+            *extra
+                .buckets
+                .get_or_insert_with(Default::default)
+                .entry(bucket)
+                .or_default() += 1;
+        }
+
+        if extra.last_post != post.id {
+            extra.last_post = post.id;
+            extra.posts += 1;
+
+            if let Some((user, mode)) = post.user {
+                extra
+                    .users
+                    .get_or_insert_with(|| Users::new(mode))
+                    .insert(user);
+            }
+        }
+    }
+
+    /// a post is only ever counted into one map, so the maps' posts are disjoint
+    pub fn merge(&mut self, other: Counts) {
+        self.count += other.count;
+
+        let (extra, other) = match (&mut self.extra, other.extra) {
+            (Some(extra), Some(other)) => (extra, *other),
+            (extra @ None, other) => {
+                *extra = other;
+                return;
+            }
+            (Some(_), None) => return,
+        };
+
+        extra.weight += other.weight;
+        extra.posts += other.posts;
+
+        match (&mut extra.users, other.users) {
+            (Some(users), Some(other)) => users.merge(other),
+            (users @ None, other) => *users = other,
+            (Some(_), None) => (),
+        }
+
+        match (&mut extra.buckets, other.buckets) {
+            (Some(buckets), Some(other)) => {
+                for (bucket, count) in other {
+                    *buckets.entry(bucket).or_default() += count;
+                }
+            }
+            (buckets @ None, other) => *buckets = other,
+            (Some(_), None) => (),
+        }
+    }
+
+    /// what the phrase is ranked by, its weight with --weight and its count otherwise
+    pub fn total(&self, config: &Config) -> Weight {
+        match config.weight {
+            Some(_) => self
+                .extra
+                .as_ref()
+                .map_or_else(Weight::default, |extra| extra.weight),
+            None => Weight::count(self.count),
+        }
+    }
+
+    /// distinct posts that contain the phrase, only counted with --document-frequency
+    pub fn posts(&self) -> u32 {
+        self.extra.as_ref().map_or(0, |extra| extra.posts)
+    }
+
+    pub fn user_count(&self) -> u64 {
+        self.extra
+            .as_ref()
+            .and_then(|extra| extra.users.as_ref())
+            .map_or(0, |users| users.count())
+    }
+
+    /// occurrences per time bucket, only counted with --buckets
+    pub fn buckets(&self) -> Option<&HashMap<i64, u32>> {
+        self.extra.as_ref()?.buckets.as_ref()
+    }
+}
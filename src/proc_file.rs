@@ -1,29 +1,108 @@
+use hashbrown::HashMap;
 use serde::de::*;
 use std::borrow::Cow;
 use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
 use unicode_segmentation::UnicodeSegmentation;
 
+use crate::dedup;
+use crate::post::{Meta, Post};
+use crate::selector::Node;
+use crate::time;
+use crate::users::User;
+use crate::Config;
+
 impl<'a> ProcFile<'a> {
-    pub fn new(phrase_counts: &'a mut crate::Map) -> Self {
-        Self { phrase_counts }
+    pub fn new(phrase_counts: &'a mut crate::Map, config: &'a Config) -> Self {
+        Self {
+            phrase_counts,
+            config,
+        }
     }
 }
 
 pub struct ProcFile<'a> {
     phrase_counts: &'a mut crate::Map,
-}
-
-#[derive(Clone, Copy)]
-enum Location {
-    Outside,
-    User,
-    Post,
-    Unknown,
+    config: &'a Config,
 }
 
 struct ProcFileValue<'a> {
     phrase_counts: &'a mut crate::Map,
-    location: Location,
+    config: &'a Config,
+    node: &'a Node,
+    /// the user everything in this value belongs to, if it is inside a user scope
+    user: Option<User>,
+    /// the phrases of the user scope this value is in, only tracked with --per-user-cap
+    seen: Option<&'a mut Seen>,
+}
+
+/// how often each phrase (by its hash) occurred so far in a user scope
+pub type Seen = HashMap<u64, u32>;
+
+/// user scopes whose id came after some of their posts, which were counted as anonymous
+pub static LATE_USER_ID_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// what a value contributes to the object it is in
+enum Found<'de> {
+    Nothing,
+    Text(Cow<'de, str>),
+    /// a user id, timestamp or score, or the ones of a nested object, e.g. `user` in
+    /// `{"user": {"id_str": ..}, ..}`
+    Meta(Meta),
+}
+
+/// the bits of the predicates on `node` that `is` says the value matches
+fn matched(config: &Config, node: &Node, is: impl Fn(&str) -> bool) -> u64 {
+    node.conditions()
+        .iter()
+        .filter(|&&i| is(config.predicates[i].value()))
+        .fold(0, |matched, &i| matched | 1 << i)
+}
+
+/// with `seen`, phrases that already occurred --per-user-cap times in it are skipped
+pub fn count_phrases(
+    config: &Config,
+    post: &Post,
+    text: &str,
+    phrase_counts: &mut crate::Map,
+    mut seen: Option<&mut Seen>,
+) {
+    let max_order = config.n.get();
+    let min_order = if config.all_orders { 1 } else { max_order };
+    let mut words = Vec::new();
+    let mut phrase = String::new();
+
+    for sentence in text.unicode_sentences() {
+        words.clear();
+        words.extend(sentence.unicode_words());
+
+        for order in min_order..=max_order {
+            for window in words.windows(order) {
+                phrase.clear();
+
+                for (i, word) in window.iter().enumerate() {
+                    if i != 0 {
+                        phrase.push(' ');
+                    }
+
+                    phrase.push_str(word);
+                }
+
+                if let (Some(seen), Some(cap)) = (seen.as_deref_mut(), config.per_user_cap) {
+                    let occurrences = seen.entry(seen.hasher().hash_one(&phrase)).or_default();
+
+                    if *occurrences >= cap.get() {
+                        continue;
+                    }
+
+                    *occurrences += 1;
+                }
+
+                crate::insert_value(&phrase, post, phrase_counts);
+            }
+        }
+    }
 }
 
 impl<'de> DeserializeSeed<'de> for ProcFile<'_> {
@@ -34,75 +113,257 @@ impl<'de> DeserializeSeed<'de> for ProcFile<'_> {
         D: Deserializer<'de>,
     {
         deserializer
-            .deserialize_map(ProcFileValue {
+            .deserialize_any(ProcFileValue {
                 phrase_counts: self.phrase_counts,
-                location: Location::Outside,
+                config: self.config,
+                node: &self.config.schema,
+                user: None,
+                seen: None,
             })
             .map(drop)
     }
 }
 
 impl<'de> DeserializeSeed<'de> for ProcFileValue<'_> {
-    type Value = Option<Cow<'de, str>>;
+    type Value = Found<'de>;
 
-    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    fn deserialize<D>(mut self, deserializer: D) -> Result<Self::Value, D::Error>
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_any(self)
+        if self.node.is_unused() {
+            return deserializer
+                .deserialize_ignored_any(IgnoredAny)
+                .map(|_| Found::Nothing);
+        }
+
+        if !self.node.is_user_scope() {
+            return deserializer.deserialize_any(self);
+        }
+
+        self.user = Some(User::anonymous());
+
+        // a user's phrases are capped within the element that holds all of their posts
+        if self.config.per_user_cap.is_none() {
+            return deserializer.deserialize_any(self);
+        }
+
+        let mut seen = Seen::new();
+
+        deserializer.deserialize_any(ProcFileValue {
+            phrase_counts: self.phrase_counts,
+            config: self.config,
+            node: self.node,
+            user: self.user,
+            seen: Some(&mut seen),
+        })
+    }
+}
+
+impl<'a> ProcFileValue<'a> {
+    fn child<'b>(&'b mut self, node: &'b Node) -> ProcFileValue<'b> {
+        ProcFileValue {
+            phrase_counts: self.phrase_counts,
+            config: self.config,
+            node,
+            user: self.user,
+            seen: self.seen.as_deref_mut(),
+        }
+    }
+
+    fn found_str<'de, E>(self, value: Cow<'de, str>) -> Result<Found<'de>, E>
+    where
+        E: Error,
+    {
+        if self.node.is_text() {
+            Ok(Found::Text(value))
+        } else {
+            self.found_meta(&value)
+        }
+    }
+
+    /// if the value is something about the post instead of its text
+    fn is_meta(&self) -> bool {
+        self.node.is_user_id()
+            || self.node.is_timestamp()
+            || self.node.is_score()
+            || !self.node.conditions().is_empty()
+    }
+
+    fn found_meta<'de, E>(self, value: &str) -> Result<Found<'de>, E>
+    where
+        E: Error,
+    {
+        if !self.is_meta() {
+            return Err(E::invalid_type(Unexpected::Str(value), &self));
+        }
+
+        let mut meta = Meta {
+            matched: matched(self.config, self.node, |expected| expected == value),
+            ..Meta::default()
+        };
+
+        if self.node.is_user_id() {
+            meta.user = Some(User::named(value));
+        }
+
+        // an empty timestamp is a missing one, an invalid one only skips its post
+        if self.node.is_timestamp() && !value.trim().is_empty() {
+            match time::parse(value) {
+                Some(timestamp) => meta.timestamp = Some(timestamp),
+                None => meta.invalid_timestamp = true,
+            }
+        }
+
+        // twitter archives quote their numbers, one that isn't a number is no score, which
+        // weighs nothing
+        if self.node.is_score() {
+            meta.score = value.trim().parse().ok();
+        }
+
+        Ok(Found::Meta(meta))
+    }
+
+    fn found_number<'de, E>(
+        self,
+        value: f64,
+        id: impl fmt::Display,
+        unexpected: Unexpected,
+    ) -> Result<Found<'de>, E>
+    where
+        E: Error,
+    {
+        if !self.is_meta() {
+            return Err(E::invalid_type(unexpected, &self));
+        }
+
+        let mut meta = Meta {
+            matched: matched(self.config, self.node, |expected| {
+                expected.parse::<f64>() == Ok(value)
+            }),
+            ..Meta::default()
+        };
+
+        if self.node.is_user_id() {
+            meta.user = Some(User::named(&id.to_string()));
+        }
+
+        if self.node.is_timestamp() {
+            match time::from_number(value) {
+                Some(timestamp) => meta.timestamp = Some(timestamp),
+                None => meta.invalid_timestamp = true,
+            }
+        }
+
+        if self.node.is_score() {
+            meta.score = Some(value);
+        }
+
+        Ok(Found::Meta(meta))
     }
 }
 
 impl<'de> Visitor<'de> for ProcFileValue<'_> {
-    type Value = Option<Cow<'de, str>>;
+    type Value = Found<'de>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("any valid JSON value")
+        let expected = [
+            (self.node.has_fields(), "an object"),
+            (self.node.elements().is_some(), "an array"),
+            (self.node.is_text() || self.node.is_user_id(), "a string"),
+            (self.node.is_user_id(), "a number"),
+            (
+                !self.node.conditions().is_empty(),
+                "a string, number or boolean",
+            ),
+        ];
+
+        let expected = expected.iter().filter(|(is_expected, _)| *is_expected);
+
+        for (i, (_, name)) in expected.enumerate() {
+            if i != 0 {
+                formatter.write_str(" or ")?;
+            }
+
+            formatter.write_str(name)?;
+        }
+
+        Ok(())
     }
 
     #[inline]
-    fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
-        Ok(None)
+    fn visit_bool<E>(self, value: bool) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        if self.node.conditions().is_empty() {
+            return Err(E::invalid_type(Unexpected::Bool(value), &self));
+        }
+
+        Ok(Found::Meta(Meta {
+            matched: matched(self.config, self.node, |expected| {
+                expected.parse::<bool>() == Ok(value)
+            }),
+            ..Meta::default()
+        }))
     }
 
     #[inline]
-    fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
-        Ok(None)
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        self.found_number(value as f64, value, Unexpected::Signed(value))
     }
 
     #[inline]
-    fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
-        Ok(None)
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        self.found_number(value as f64, value, Unexpected::Unsigned(value))
     }
 
     #[inline]
-    fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
-        Ok(None)
+    fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        self.found_number(value, value, Unexpected::Float(value))
     }
 
     fn visit_borrowed_str<E>(self, value: &'de str) -> Result<Self::Value, E>
     where
-        E: serde::de::Error,
+        E: Error,
     {
-        Ok(Some(value.into()))
+        self.found_str(value.into())
     }
 
+    // only reached when the input is streamed, so avoid copying anything but text
     #[inline]
     fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
     where
-        E: serde::de::Error,
+        E: Error,
     {
-        self.visit_string(value.into())
+        if self.node.is_text() {
+            Ok(Found::Text(value.to_owned().into()))
+        } else {
+            self.found_meta(value)
+        }
     }
 
     #[inline]
-    fn visit_string<E>(self, value: String) -> Result<Self::Value, E> {
-        Ok(Some(value.into()))
+    fn visit_string<E>(self, value: String) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        self.found_str(value.into())
     }
 
+    // missing values are fine anywhere
     #[inline]
     fn visit_none<E>(self) -> Result<Self::Value, E> {
-        Ok(None)
+        Ok(Found::Nothing)
     }
 
     #[inline]
@@ -110,69 +371,251 @@ impl<'de> Visitor<'de> for ProcFileValue<'_> {
     where
         D: serde::Deserializer<'de>,
     {
-        Deserialize::deserialize(deserializer)
+        self.deserialize(deserializer)
     }
 
     #[inline]
     fn visit_unit<E>(self) -> Result<Self::Value, E> {
-        Ok(None)
+        Ok(Found::Nothing)
     }
 
     #[inline]
-    fn visit_seq<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
+    fn visit_seq<V>(mut self, mut visitor: V) -> Result<Self::Value, V::Error>
     where
         V: SeqAccess<'de>,
     {
-        while let Some(_) = visitor.next_element_seed(ProcFileValue {
-            phrase_counts: self.phrase_counts,
-            location: self.location,
-        })? {}
+        let node = match self.node.elements() {
+            Some(node) => node,
+            None => return Err(V::Error::invalid_type(Unexpected::Seq, &self)),
+        };
+
+        while visitor.next_element_seed(self.child(node))?.is_some() {}
 
-        Ok(None)
+        Ok(Found::Nothing)
     }
 
-    fn visit_map<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
+    fn visit_map<V>(mut self, mut visitor: V) -> Result<Self::Value, V::Error>
     where
         V: MapAccess<'de>,
     {
-        while let Some(key) = visitor.next_key()? {
-            let _: Cow<str> = key;
-            let key = key.as_ref();
+        if !self.node.has_fields() {
+            return Err(V::Error::invalid_type(Unexpected::Map, &self));
+        }
+
+        // the object's text fields are counted together once it ends, as one post
+        let mut texts = Vec::new();
+        let mut meta = Meta::default();
+        let mut posts_before_id = false;
 
-            let location = match (self.location, key) {
-                (Location::Outside, "users") => Location::User,
-                (Location::User, "posts") => Location::Post,
-                (Location::Outside, _) | (Location::User, _) => Location::Unknown,
-                (location, _) => location,
+        while let Some(node) = visitor.next_key_seed(Field(self.node))? {
+            let node = match node {
+                Some(node) => node,
+                None => {
+                    let _: IgnoredAny = visitor.next_value()?;
+                    continue;
+                }
             };
 
-            if let Location::Unknown = location {
-                let _: IgnoredAny = visitor.next_value()?;
-                continue;
+            let user = meta.user;
+
+            match visitor.next_value_seed(self.child(node))? {
+                Found::Text(text) => {
+                    // a field can be counted and be the user id at the same time
+                    if node.is_user_id() {
+                        meta.user = Some(User::named(&text));
+                    }
+
+                    meta.matched |= matched(self.config, node, |expected| expected == text);
+
+                    texts.push(text);
+                }
+                Found::Meta(found) => meta.merge(found),
+                Found::Nothing => (),
             }
 
-            let value = visitor.next_value_seed(ProcFileValue {
-                phrase_counts: self.phrase_counts,
-                location,
-            })?;
+            // the id of a user scope applies to the posts in the fields after it
+            if self.node.is_user_scope() {
+                if meta.user != user {
+                    if posts_before_id {
+                        LATE_USER_ID_COUNT.fetch_add(1, Relaxed);
+                    }
+
+                    self.user = meta.user;
+                }
 
-            match location {
-                Location::Post => (),
-                _ => continue,
+                posts_before_id |= node.has_text();
             }
+        }
+
+        if texts.is_empty() {
+            return Ok(Found::Meta(meta));
+        }
+
+        meta.user = meta.user.or(self.user);
+
+        // filtered out posts are skipped before their text is split into words
+        let post = match Post::new(self.config, &meta) {
+            Some(post) => post,
+            None => return Ok(Found::Nothing),
+        };
+
+        if dedup::is_duplicate(self.config, texts.iter().map(|text| &**text)) {
+            return Ok(Found::Nothing);
+        }
+
+        for text in texts {
+            count_phrases(
+                self.config,
+                &post,
+                &text,
+                self.phrase_counts,
+                self.seen.as_deref_mut(),
+            );
+        }
+
+        Ok(Found::Nothing)
+    }
+}
 
-            if key == "text" || key == "description" {
-                value
-                    .unwrap()
-                    .as_ref()
-                    .unicode_sentences()
-                    .map(UnicodeSegmentation::unicode_words)
-                    .flat_map(itertools::Itertools::tuple_windows)
-                    .map(crate::config::get_chunks)
-                    .for_each(|chunk| crate::insert_value(chunk, 1, self.phrase_counts));
+/// looks up the selector for a key without allocating the key
+struct Field<'a>(&'a Node);
+
+impl<'de, 'a> DeserializeSeed<'de> for Field<'a> {
+    type Value = Option<&'a Node>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for Field<'a> {
+    type Value = Option<&'a Node>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string key")
+    }
+
+    fn visit_str<E>(self, key: &str) -> Result<Self::Value, E> {
+        Ok(self.0.field(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config, counts};
+
+    /// the reason the document failed, if it did
+    fn count(args: &[&str], json: &str) -> Result<crate::Map, &'static str> {
+        let config = config(args);
+        let mut phrase_counts = crate::Map::new();
+        let mut deserializer = serde_json::Deserializer::from_str(json);
+
+        ProcFile::new(&mut phrase_counts, &config)
+            .deserialize(&mut deserializer)
+            .and_then(|()| deserializer.end())
+            .map_err(|err| crate::failure::Failure::from_json(err).reason())?;
+
+        Ok(phrase_counts)
+    }
+
+    fn count_of(phrase_counts: &crate::Map, phrase: &str) -> u32 {
+        counts(phrase_counts, phrase).map_or(0, |counts| counts.count)
+    }
+
+    fn users_of(phrase_counts: &crate::Map, phrase: &str) -> u64 {
+        counts(phrase_counts, phrase).map_or(0, |counts| counts.user_count())
+    }
+
+    #[test]
+    fn only_selected_values_are_counted() {
+        let json = r#"{
+            "kind": "Listing",
+            "data": {
+                "children": [
+                    {"body": "red", "title": "blue", "replies": [{"body": "green"}]},
+                    {"body": "fox", "edited": false, "awards": {"gold": 1}},
+                    {"body": null},
+                    {},
+                    null
+                ]
             }
+        }"#;
+
+        let phrase_counts = count(&["--select", "data.children[*].body"], json).unwrap();
+        assert_eq!(count_of(&phrase_counts, "red"), 1);
+        assert_eq!(count_of(&phrase_counts, "fox"), 1);
+        assert_eq!(count_of(&phrase_counts, "blue"), 0);
+        assert_eq!(count_of(&phrase_counts, "green"), 0);
+    }
+
+    #[test]
+    fn unexpected_shapes_fail() {
+        let args = ["--select", "data.children[*].body"];
+
+        for json in [
+            r#"{"data": {"children": {"body": "red"}}}"#,
+            r#"{"data": {"children": [{"body": 1}]}}"#,
+            r#"{"data": {"children": [{"body": ["red"]}]}}"#,
+            r#"{"data": []}"#,
+            r#""data""#,
+        ] {
+            assert_eq!(count(&args, json).err(), Some("shape"), "{}", json);
         }
 
-        Ok(None)
+        assert_eq!(
+            count(&args, r#"{"data": {"children": [{"body": "red"}"#).err(),
+            Some("truncated")
+        );
+        assert_eq!(count(&args, r#"{"data": "a"} {}"#).err(), Some("shape"));
+    }
+
+    #[test]
+    fn user_scope_ids() {
+        let args = [
+            "--select",
+            "users[*].posts[*].text",
+            "--user",
+            "users[*].id",
+            "--distinct-users",
+            "exact",
+        ];
+        let json = r#"{"users": [
+            {"id": "a", "posts": [{"text": "red"}, {"text": "red fox"}]},
+            {"id": 7, "posts": [{"text": "red"}]},
+            {"posts": [{"text": "fox"}]},
+            {"posts": [{"text": "fox"}], "id": "a"},
+            {"id": "a", "posts": [{"text": "blue"}]}
+        ]}"#;
+
+        let phrase_counts = count(&args, json).unwrap();
+        assert_eq!(count_of(&phrase_counts, "red"), 3);
+        assert_eq!(users_of(&phrase_counts, "red"), 2);
+        // the posts before an id, and outside of any id, belong to anonymous users
+        assert_eq!(count_of(&phrase_counts, "fox"), 3);
+        assert_eq!(users_of(&phrase_counts, "fox"), 3);
+        assert_eq!(users_of(&phrase_counts, "blue"), 1);
+    }
+
+    #[test]
+    fn per_user_cap() {
+        let json = r#"{"users": [
+            {"posts": [{"text": "red red fox"}, {"text": "red"}]},
+            {"posts": [{"text": "red", "description": "red"}]}
+        ]}"#;
+
+        let phrase_counts = count(&["--schema", "posts"], json).unwrap();
+        assert_eq!(count_of(&phrase_counts, "red"), 5);
+
+        // within each element of users[*]
+        let phrase_counts = count(&["--schema", "posts", "--per-user-cap", "2"], json).unwrap();
+        assert_eq!(count_of(&phrase_counts, "red"), 4);
+        assert_eq!(count_of(&phrase_counts, "fox"), 1);
+
+        let phrase_counts = count(&["--schema", "posts", "--per-user-cap", "1"], json).unwrap();
+        assert_eq!(count_of(&phrase_counts, "red"), 2);
     }
 }
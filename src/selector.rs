@@ -0,0 +1,584 @@
+use hashbrown::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// a path to the text fields that should be tokenized, e.g.
+/// `users[*].posts[*].{text,description}` or `data.children[*].body`
+#[derive(Debug, Clone)]
+pub struct Selector {
+    steps: Vec<Step>,
+}
+
+/// a path to the user of each post, either a field holding the user id, e.g. `author`, or
+/// values that each belong to a different user, e.g. `users[*]`
+#[derive(Debug, Clone)]
+pub struct UserSelector {
+    steps: Vec<Step>,
+}
+
+/// `selector==value` or `selector!=value`, compared with the string, number or boolean
+/// at the end of the selector
+#[derive(Debug, Clone)]
+pub struct Predicate {
+    selector: Selector,
+    equal: bool,
+    value: Box<str>,
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    /// `key` or `{key,other}`
+    Fields(Vec<Box<str>>),
+    /// `[*]`, every element of an array
+    Elements,
+}
+
+/// named sets of selectors for common dumps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preset {
+    /// `{"users": [{"posts": [{"text": .., "description": ..}]}]}`
+    #[default]
+    Posts,
+    /// reddit comment and submission dumps, one object per line
+    Reddit,
+    /// twitter archives (`[{"tweet": {..}}]`) and api dumps
+    Twitter,
+}
+
+impl Preset {
+    pub const NAMES: &'static [&'static str] = &["posts", "reddit", "twitter"];
+
+    pub fn selectors(self) -> &'static [&'static str] {
+        match self {
+            Preset::Posts => &["users[*].posts[*].{text,description}"],
+            Preset::Reddit => &[
+                "{body,selftext,title}",
+                "data.children[*].data.{body,selftext,title}",
+            ],
+            Preset::Twitter => &["{full_text}", "[*].{full_text}", "[*].tweet.{full_text}"],
+        }
+    }
+
+    pub fn users(self) -> &'static [&'static str] {
+        match self {
+            Preset::Posts => &["users[*]"],
+            Preset::Reddit => &["author", "data.children[*].data.author"],
+            // archives only hold the tweets of their owner
+            Preset::Twitter => &["user.id_str", "[*].user.id_str"],
+        }
+    }
+
+    pub fn timestamps(self) -> &'static [&'static str] {
+        match self {
+            Preset::Posts => &[],
+            Preset::Reddit => &["created_utc", "data.children[*].data.created_utc"],
+            Preset::Twitter => &["created_at", "[*].created_at", "[*].tweet.created_at"],
+        }
+    }
+
+    pub fn scores(self) -> &'static [&'static str] {
+        match self {
+            Preset::Posts => &[],
+            Preset::Reddit => &["score", "data.children[*].data.score"],
+            Preset::Twitter => &[
+                "favorite_count",
+                "[*].favorite_count",
+                "[*].tweet.favorite_count",
+            ],
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "posts" => Ok(Preset::Posts),
+            "reddit" => Ok(Preset::Reddit),
+            "twitter" => Ok(Preset::Twitter),
+            _ => Err(format!(
+                "unknown schema `{}`, expected one of {}",
+                name,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// all selectors merged into a tree, so that documents can be walked in a single pass
+#[derive(Debug, Default)]
+pub struct Node {
+    fields: HashMap<Box<str>, Node>,
+    elements: Option<Box<Node>>,
+    text: bool,
+    user_id: bool,
+    user_scope: bool,
+    timestamp: bool,
+    score: bool,
+    /// indices of the predicates that compare this node's value
+    conditions: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    selector: String,
+    message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid selector `{}`: {}", self.selector, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl FromStr for Selector {
+    type Err = ParseError;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        let steps = parse_steps(selector)?;
+
+        match steps.last() {
+            Some(Step::Fields(_)) => Ok(Self { steps }),
+            _ => Err(ParseError {
+                selector: selector.to_owned(),
+                message: "must end with the name of a text field",
+            }),
+        }
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = ParseError;
+
+    fn from_str(predicate: &str) -> Result<Self, Self::Err> {
+        let (at, equal) = match (predicate.find("=="), predicate.find("!=")) {
+            (Some(eq), Some(ne)) => (eq.min(ne), eq < ne),
+            (Some(eq), None) => (eq, true),
+            (None, Some(ne)) => (ne, false),
+            (None, None) => {
+                return Err(ParseError {
+                    selector: predicate.to_owned(),
+                    message: "expected `==` or `!=`",
+                })
+            }
+        };
+
+        Ok(Self {
+            selector: predicate[..at].trim().parse()?,
+            equal,
+            value: strip_quotes(predicate[at + 2..].trim()).into(),
+        })
+    }
+}
+
+/// `lang=="en"` is the same as `lang==en`
+fn strip_quotes(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+impl Predicate {
+    /// if the post has to have the value, instead of not having it
+    pub fn is_equal(&self) -> bool {
+        self.equal
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// the column a predicate of a single field refers to in csv files
+    pub fn column(&self) -> Option<&str> {
+        match &self.selector.steps[..] {
+            [Step::Fields(fields)] if fields.len() == 1 => Some(&fields[0]),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for UserSelector {
+    type Err = ParseError;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        parse_steps(selector).map(|steps| Self { steps })
+    }
+}
+
+fn parse_steps(selector: &str) -> Result<Vec<Step>, ParseError> {
+    let error = |message| ParseError {
+        selector: selector.to_owned(),
+        message,
+    };
+
+    let mut steps = Vec::new();
+    let mut rest = selector.trim();
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            if steps.is_empty() || after.is_empty() || after.starts_with('.') {
+                return Err(error("empty path segment"));
+            }
+
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('[') {
+            rest = after
+                .strip_prefix("*]")
+                .ok_or_else(|| error("only `[*]` is supported inside brackets"))?;
+            steps.push(Step::Elements);
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after.find('}').ok_or_else(|| error("unclosed `{`"))?;
+            let fields = after[..end]
+                .split(',')
+                .map(str::trim)
+                .map(Box::from)
+                .collect::<Vec<Box<str>>>();
+
+            if fields.iter().any(|field| field.is_empty()) {
+                return Err(error("empty field name"));
+            }
+
+            rest = &after[end + 1..];
+            steps.push(Step::Fields(fields));
+        } else {
+            let end = rest
+                .find(&['.', '[', '{', '}', ']'][..])
+                .unwrap_or(rest.len());
+
+            if end == 0 {
+                return Err(error("unexpected character"));
+            }
+
+            steps.push(Step::Fields(vec![rest[..end].into()]));
+            rest = &rest[end..];
+        }
+    }
+
+    if steps.is_empty() {
+        return Err(error("empty selector"));
+    }
+
+    Ok(steps)
+}
+
+impl Node {
+    pub fn new<'a>(selectors: impl IntoIterator<Item = &'a Selector>) -> Self {
+        let mut root = Self::default();
+
+        for selector in selectors {
+            root.insert(&selector.steps, Mark::Text);
+        }
+
+        root
+    }
+
+    pub fn from_preset(preset: Preset) -> Self {
+        let selectors = preset
+            .selectors()
+            .iter()
+            .map(|selector| selector.parse().expect("presets are valid selectors"))
+            .collect::<Vec<Selector>>();
+
+        Self::new(&selectors)
+    }
+
+    pub fn add_users<'a>(&mut self, selectors: impl IntoIterator<Item = &'a UserSelector>) {
+        for selector in selectors {
+            let steps = &selector.steps[..];
+
+            match steps {
+                [.., Step::Elements] => self.insert(steps, Mark::UserScope),
+                // the id of an element that holds a user's posts, e.g. `users[*].id`, makes the
+                // element a user scope, so that the posts after the id belong to that user
+                [scope @ .., Step::Elements, Step::Fields(_)]
+                    if !self.has_direct_text(&steps[..scope.len() + 1]) =>
+                {
+                    self.insert(&steps[..scope.len() + 1], Mark::UserScope);
+                    self.insert(steps, Mark::UserId);
+                }
+                _ => self.insert(steps, Mark::UserId),
+            }
+        }
+    }
+
+    pub fn add_preset_users(&mut self, preset: Preset) {
+        let selectors = preset
+            .users()
+            .iter()
+            .map(|selector| selector.parse().expect("presets are valid selectors"))
+            .collect::<Vec<UserSelector>>();
+
+        self.add_users(&selectors)
+    }
+
+    pub fn add_timestamps<'a>(&mut self, selectors: impl IntoIterator<Item = &'a Selector>) {
+        for selector in selectors {
+            self.insert(&selector.steps, Mark::Timestamp);
+        }
+    }
+
+    pub fn add_preset_timestamps(&mut self, preset: Preset) {
+        let selectors = preset
+            .timestamps()
+            .iter()
+            .map(|selector| selector.parse().expect("presets are valid selectors"))
+            .collect::<Vec<Selector>>();
+
+        self.add_timestamps(&selectors)
+    }
+
+    pub fn add_scores<'a>(&mut self, selectors: impl IntoIterator<Item = &'a Selector>) {
+        for selector in selectors {
+            self.insert(&selector.steps, Mark::Score);
+        }
+    }
+
+    pub fn add_preset_scores(&mut self, preset: Preset) {
+        let selectors = preset
+            .scores()
+            .iter()
+            .map(|selector| selector.parse().expect("presets are valid selectors"))
+            .collect::<Vec<Selector>>();
+
+        self.add_scores(&selectors)
+    }
+
+    pub fn add_predicates(&mut self, predicates: &[Predicate]) {
+        for (i, predicate) in predicates.iter().enumerate() {
+            self.insert(&predicate.selector.steps, Mark::Condition(i));
+        }
+    }
+
+    fn insert(&mut self, steps: &[Step], mark: Mark) {
+        let (step, rest) = match steps.split_first() {
+            Some(x) => x,
+            None => {
+                match mark {
+                    Mark::Text => self.text = true,
+                    Mark::UserId => self.user_id = true,
+                    Mark::UserScope => self.user_scope = true,
+                    Mark::Timestamp => self.timestamp = true,
+                    Mark::Score => self.score = true,
+                    Mark::Condition(i) => self.conditions.push(i),
+                }
+                return;
+            }
+        };
+
+        match step {
+            Step::Elements => self
+                .elements
+                .get_or_insert_with(Default::default)
+                .insert(rest, mark),
+            Step::Fields(fields) => {
+                for field in fields {
+                    self.fields
+                        .entry(field.clone())
+                        .or_default()
+                        .insert(rest, mark);
+                }
+            }
+        }
+    }
+
+    /// if a value at `steps` has text fields itself, i.e. it is a post
+    fn has_direct_text(&self, steps: &[Step]) -> bool {
+        match steps.split_first() {
+            None => self.fields.values().any(|node| node.text),
+            Some((Step::Elements, rest)) => self
+                .elements
+                .as_ref()
+                .is_some_and(|node| node.has_direct_text(rest)),
+            Some((Step::Fields(fields), rest)) => fields.iter().any(|field| {
+                self.fields
+                    .get(field)
+                    .is_some_and(|node| node.has_direct_text(rest))
+            }),
+        }
+    }
+
+    /// if this node or any node inside it is text
+    pub fn has_text(&self) -> bool {
+        self.text
+            || self.elements.iter().any(|node| node.has_text())
+            || self.fields.values().any(Node::has_text)
+    }
+
+    /// if any node is a user id, otherwise every user is anonymous
+    pub fn has_user_id(&self) -> bool {
+        self.user_id
+            || self.elements.iter().any(|node| node.has_user_id())
+            || self.fields.values().any(Node::has_user_id)
+    }
+
+    /// if any node is a timestamp, otherwise no post has one
+    pub fn has_timestamp(&self) -> bool {
+        self.timestamp
+            || self.elements.iter().any(|node| node.has_timestamp())
+            || self.fields.values().any(Node::has_timestamp)
+    }
+
+    /// if any node is a score, otherwise every post weighs nothing
+    pub fn has_score(&self) -> bool {
+        self.score
+            || self.elements.iter().any(|node| node.has_score())
+            || self.fields.values().any(Node::has_score)
+    }
+
+    pub fn field(&self, key: &str) -> Option<&Self> {
+        self.fields.get(key)
+    }
+
+    pub fn has_fields(&self) -> bool {
+        !self.fields.is_empty()
+    }
+
+    pub fn elements(&self) -> Option<&Self> {
+        self.elements.as_deref()
+    }
+
+    /// if this node's value should be tokenized
+    pub fn is_text(&self) -> bool {
+        self.text
+    }
+
+    /// if this node's value identifies the user of the post it is in
+    pub fn is_user_id(&self) -> bool {
+        self.user_id
+    }
+
+    /// if nothing inside this node's value is counted, e.g. a user scope with no text
+    pub fn is_unused(&self) -> bool {
+        !self.has_fields()
+            && self.elements.is_none()
+            && !self.text
+            && !self.user_id
+            && !self.timestamp
+            && !self.score
+            && self.conditions.is_empty()
+    }
+
+    pub fn conditions(&self) -> &[usize] {
+        &self.conditions
+    }
+
+    /// if this node's value is the time the post it is in was made
+    pub fn is_timestamp(&self) -> bool {
+        self.timestamp
+    }
+
+    /// if this node's value is the score the post it is in is weighted by
+    pub fn is_score(&self) -> bool {
+        self.score
+    }
+
+    /// if everything inside this node's value belongs to a single user
+    pub fn is_user_scope(&self) -> bool {
+        self.user_scope
+    }
+
+    /// if this node or any node inside it is a user scope
+    pub fn has_user_scope(&self) -> bool {
+        self.user_scope
+            || self.elements.iter().any(|node| node.has_user_scope())
+            || self.fields.values().any(Node::has_user_scope)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Mark {
+    Text,
+    UserId,
+    UserScope,
+    Timestamp,
+    Score,
+    Condition(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the steps as written, with `[*]` for elements
+    fn steps(selector: &str) -> Vec<String> {
+        parse_steps(selector)
+            .unwrap()
+            .iter()
+            .map(|step| match step {
+                Step::Fields(fields) => fields.join(","),
+                Step::Elements => "[*]".to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid_selectors() {
+        assert_eq!(steps("body"), ["body"]);
+        assert_eq!(
+            steps("data.children[*].body"),
+            ["data", "children", "[*]", "body"]
+        );
+        assert_eq!(
+            steps(" users[*].posts[*].{text, description} "),
+            ["users", "[*]", "posts", "[*]", "text,description"]
+        );
+        assert_eq!(steps("[*].body"), ["[*]", "body"]);
+        assert_eq!(steps("users[*]"), ["users", "[*]"]);
+    }
+
+    #[test]
+    fn invalid_selectors() {
+        for selector in [
+            "",
+            " ",
+            ".body",
+            "data..body",
+            "data.",
+            "data[0]",
+            "{text",
+            "{text,}",
+            "body}",
+        ] {
+            assert!(parse_steps(selector).is_err(), "{:?}", selector);
+        }
+    }
+
+    #[test]
+    fn text_selectors_end_in_a_field() {
+        assert!("users[*].posts[*].text".parse::<Selector>().is_ok());
+        assert!("users[*]".parse::<Selector>().is_err());
+        assert!("users[*]".parse::<UserSelector>().is_ok());
+    }
+
+    #[test]
+    fn predicates() {
+        let predicate = "lang==en".parse::<Predicate>().unwrap();
+        assert!(predicate.is_equal());
+        assert_eq!(predicate.value(), "en");
+        assert_eq!(predicate.column(), Some("lang"));
+
+        let predicate = r#" data.lang != "en" "#.parse::<Predicate>().unwrap();
+        assert!(!predicate.is_equal());
+        assert_eq!(predicate.value(), "en");
+        assert_eq!(predicate.column(), None);
+
+        assert!("lang=en".parse::<Predicate>().is_err());
+        assert!("users[*]==en".parse::<Predicate>().is_err());
+        assert!("==en".parse::<Predicate>().is_err());
+    }
+
+    #[test]
+    fn the_first_operator_splits_a_predicate() {
+        let predicate = "title==a!=b".parse::<Predicate>().unwrap();
+        assert!(predicate.is_equal());
+        assert_eq!(predicate.value(), "a!=b");
+
+        let predicate = "title!=a==b".parse::<Predicate>().unwrap();
+        assert!(!predicate.is_equal());
+        assert_eq!(predicate.value(), "a==b");
+    }
+}
@@ -0,0 +1,213 @@
+use pulldown_cmark::{Event, Parser, Tag};
+use std::io::{self, BufRead};
+
+use crate::dedup;
+use crate::input::Format;
+use crate::post::{Meta, Post};
+use crate::proc_file::count_phrases;
+
+pub fn process_text(
+    config: &crate::Config,
+    format: Format,
+    text: &str,
+    phrase_counts: &mut crate::Map,
+) {
+    // with --paragraphs every paragraph is checked for duplicates on its own
+    if let Some(post) = Post::new(config, &Meta::default()) {
+        if config.paragraphs || !dedup::is_duplicate(config, Some(text)) {
+            count_text(config, format, &post, text, phrase_counts);
+        }
+    }
+}
+
+/// count `text` as part of `post`, or every paragraph as its own post with --paragraphs
+fn count_text(
+    config: &crate::Config,
+    format: Format,
+    post: &Post,
+    text: &str,
+    phrase_counts: &mut crate::Map,
+) {
+    let stripped;
+    let text = if format == Format::Markdown && config.strip_markdown {
+        stripped = strip_markdown(text);
+        &stripped[..]
+    } else {
+        text
+    };
+
+    if config.paragraphs {
+        for paragraph in paragraphs(text) {
+            match Post::new(config, &Meta::default()) {
+                Some(post) if !dedup::is_duplicate(config, Some(paragraph)) => {
+                    count_phrases(config, &post, paragraph, phrase_counts, None)
+                }
+                _ => (),
+            }
+        }
+    } else {
+        count_phrases(config, post, text, phrase_counts, None);
+    }
+}
+
+/// count `file` one paragraph at a time, a paragraph ends at a blank line like in
+/// `paragraphs`, except inside fenced code blocks of markdown that is stripped, so that
+/// the counts are the same as if the file was read into memory
+pub fn stream_text(
+    config: &crate::Config,
+    format: Format,
+    paragraph: &mut String,
+    mut file: impl BufRead,
+    phrase_counts: &mut crate::Map,
+) -> io::Result<()> {
+    // text files have no metadata, so any filter drops the whole file
+    let post = match Post::new(config, &Meta::default()) {
+        Some(post) => post,
+        None => return Ok(()),
+    };
+    let track_fences = format == Format::Markdown && config.strip_markdown;
+    let mut open_fence = None;
+    paragraph.clear();
+
+    // whether the whole file is a duplicate is only known once it was read, so until then
+    // its phrases are counted on their own
+    let dedup = config.dedup && !config.paragraphs;
+    let mut hash = dedup::Incremental::new();
+    let mut file_counts = crate::Map::new();
+    let counts = if dedup {
+        &mut file_counts
+    } else {
+        &mut *phrase_counts
+    };
+
+    loop {
+        let start = paragraph.len();
+        let read = file.read_line(paragraph)?;
+        let line = &paragraph[start..];
+
+        if dedup {
+            hash.write(line);
+        }
+
+        if track_fences {
+            open_fence = match (open_fence, fence(line)) {
+                (None, Some((marker, len, _))) => Some((marker, len)),
+                (Some((marker, len)), Some((closing, closing_len, true)))
+                    if closing == marker && closing_len >= len =>
+                {
+                    None
+                }
+                (open_fence, _) => open_fence,
+            };
+        }
+
+        if read == 0 || (is_blank(line) && open_fence.is_none()) {
+            if !is_blank(paragraph) {
+                count_text(config, format, &post, paragraph, counts);
+            }
+
+            paragraph.clear();
+        }
+
+        if read == 0 {
+            break;
+        }
+    }
+
+    if dedup && !hash.is_duplicate(config) {
+        crate::merge(phrase_counts, file_counts);
+    }
+
+    Ok(())
+}
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+/// the marker character and length of a markdown code fence (```` ``` ```` or `~~~`), and
+/// if the fence could close a block, i.e. it has no info string
+fn fence(line: &str) -> Option<(char, usize, bool)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    let line = line.trim_start_matches(' ');
+    let marker = line.chars().next().filter(|&c| c == '`' || c == '~')?;
+    let len = line.len() - line.trim_start_matches(marker).len();
+
+    if indent > 3 || len < 3 {
+        return None;
+    }
+
+    Some((marker, len, is_blank(&line[len..])))
+}
+
+/// split on blank lines, lines with only whitespace are blank too
+fn paragraphs(text: &str) -> impl Iterator<Item = &str> {
+    let mut lines = text.split_inclusive('\n');
+    let mut offset = 0;
+
+    std::iter::from_fn(move || {
+        let mut start = None;
+        let mut end = offset;
+
+        for line in lines.by_ref() {
+            let line_start = end;
+            end += line.len();
+
+            match start {
+                None if is_blank(line) => (),
+                None => start = Some(line_start),
+                Some(_) if is_blank(line) => break,
+                Some(_) => (),
+            }
+        }
+
+        offset = end;
+        start.map(|start| text[start..end].trim_end())
+    })
+}
+
+/// keep only the prose of a markdown document, block boundaries become blank lines so
+/// that paragraphs are still separated and code blocks are dropped entirely
+fn strip_markdown(markdown: &str) -> String {
+    let mut text = String::with_capacity(markdown.len());
+    let mut code_block_depth = 0_u32;
+
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => code_block_depth += 1,
+            Event::End(Tag::CodeBlock(_)) => code_block_depth -= 1,
+            Event::Text(value) | Event::Code(value) if code_block_depth == 0 => {
+                text.push_str(&value)
+            }
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::End(Tag::Paragraph) | Event::End(Tag::Heading(..)) | Event::End(Tag::Item) => {
+                text.push_str("\n\n")
+            }
+            _ => (),
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(text: &str) -> Vec<&str> {
+        paragraphs(text).collect()
+    }
+
+    #[test]
+    fn blank_lines_end_paragraphs() {
+        assert_eq!(split("one\ntwo\n\nthree\n"), ["one\ntwo", "three"]);
+        assert_eq!(split("one\n \t\n\n\nthree  "), ["one", "three"]);
+        assert_eq!(split("\n\n  one\r\n\r\ntwo"), ["  one", "two"]);
+    }
+
+    #[test]
+    fn no_paragraphs() {
+        assert!(split("").is_empty());
+        assert!(split(" \n\n\t\n").is_empty());
+    }
+}
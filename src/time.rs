@@ -0,0 +1,192 @@
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use std::str::FromStr;
+
+/// numbers above this are taken to be milliseconds, in seconds it would be the year 5138
+const MILLISECONDS_FROM: i64 = 100_000_000_000;
+
+/// the size of the time buckets phrases are counted in, all buckets are in utc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bucket {
+    Hour,
+    Day,
+    /// iso weeks, starting on monday
+    Week,
+    Month,
+}
+
+impl Bucket {
+    pub const NAMES: &'static [&'static str] = &["hour", "day", "week", "month"];
+
+    /// the start of the bucket `timestamp` falls into, in seconds since the epoch
+    pub fn of(self, timestamp: i64) -> Option<i64> {
+        let time = Utc.timestamp_opt(timestamp, 0).single()?.naive_utc();
+        let date = time.date();
+
+        let start = match self {
+            Bucket::Hour => date.and_hms_opt(time.hour(), 0, 0)?,
+            Bucket::Day => date.and_hms_opt(0, 0, 0)?,
+            Bucket::Week => {
+                let monday =
+                    date.checked_sub_days(Days::new(date.weekday().num_days_from_monday().into()))?;
+                monday.and_hms_opt(0, 0, 0)?
+            }
+            Bucket::Month => date.with_day(1)?.and_hms_opt(0, 0, 0)?,
+        };
+
+        Some(start.and_utc().timestamp())
+    }
+
+    /// the column name of the bucket starting at `start`, e.g. `2021-03` for months
+    pub fn label(self, start: i64) -> String {
+        let start = match Utc.timestamp_opt(start, 0).single() {
+            Some(start) => start,
+            None => return start.to_string(),
+        };
+
+        let format = match self {
+            Bucket::Hour => "%Y-%m-%dT%H:00",
+            Bucket::Day => "%Y-%m-%d",
+            Bucket::Week => "%G-W%V",
+            Bucket::Month => "%Y-%m",
+        };
+
+        start.format(format).to_string()
+    }
+}
+
+impl FromStr for Bucket {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "hour" => Ok(Bucket::Hour),
+            "day" => Ok(Bucket::Day),
+            "week" => Ok(Bucket::Week),
+            "month" => Ok(Bucket::Month),
+            _ => Err(format!(
+                "unknown bucket `{}`, expected one of {}",
+                name,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// for --since and --until
+pub fn parse_arg(timestamp: &str) -> Result<i64, String> {
+    parse(timestamp).ok_or_else(|| format!("invalid time `{}`", timestamp))
+}
+
+/// unix timestamps in seconds (or milliseconds)
+pub fn from_number(timestamp: f64) -> Option<i64> {
+    if !timestamp.is_finite() {
+        return None;
+    }
+
+    let timestamp = timestamp as i64;
+
+    if timestamp.abs() >= MILLISECONDS_FROM {
+        Some(timestamp / 1000)
+    } else {
+        Some(timestamp)
+    }
+}
+
+/// unix timestamps, rfc 3339 (`2021-03-04T05:06:07Z`), `2021-03-04 05:06:07` and
+/// `2021-03-04` in utc, or twitter's `Thu Mar 04 05:06:07 +0000 2021`
+pub fn parse(timestamp: &str) -> Option<i64> {
+    let timestamp = timestamp.trim();
+
+    if let Ok(number) = timestamp.parse::<f64>() {
+        return from_number(number);
+    }
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(timestamp) {
+        return Some(time.timestamp());
+    }
+
+    if let Ok(time) = DateTime::parse_from_str(timestamp, "%a %b %d %H:%M:%S %z %Y") {
+        return Some(time.timestamp());
+    }
+
+    if let Ok(time) = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f") {
+        return Some(time.and_utc().timestamp());
+    }
+
+    NaiveDate::parse_from_str(timestamp, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc().timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2021-05-03T00:00:00Z
+    const MAY_3: i64 = 1_620_000_000;
+
+    #[test]
+    fn formats() {
+        for timestamp in [
+            "1620000000",
+            " 1620000000.5 ",
+            "2021-05-03T00:00:00Z",
+            "2021-05-03T02:00:00+02:00",
+            "2021-05-03 00:00:00",
+            "2021-05-03 00:00:00.250",
+            "2021-05-03",
+            "Mon May 03 00:00:00 +0000 2021",
+        ] {
+            assert_eq!(parse(timestamp), Some(MAY_3), "{:?}", timestamp);
+        }
+    }
+
+    #[test]
+    fn invalid_timestamps() {
+        for timestamp in ["", "yesterday", "2021-13-01", "03/05/2021", "NaN"] {
+            assert_eq!(parse(timestamp), None, "{:?}", timestamp);
+        }
+    }
+
+    #[test]
+    fn milliseconds() {
+        assert_eq!(parse("1620000000000"), Some(MAY_3));
+        assert_eq!(parse("-1620000000000"), Some(-MAY_3));
+        assert_eq!(from_number(1_620_000_000_123.0), Some(MAY_3));
+        assert_eq!(from_number(99_999_999_999.0), Some(99_999_999_999));
+        assert_eq!(from_number(100_000_000_000.0), Some(100_000_000));
+        assert_eq!(from_number(f64::INFINITY), None);
+    }
+
+    #[test]
+    fn buckets() {
+        // a monday
+        assert_eq!(Bucket::Hour.of(MAY_3 + 3_725), Some(MAY_3 + 3_600));
+        assert_eq!(Bucket::Day.of(MAY_3 + 3_725), Some(MAY_3));
+        assert_eq!(Bucket::Week.of(MAY_3 + 6 * 86_400 + 5), Some(MAY_3));
+        assert_eq!(Bucket::Week.of(MAY_3 - 1), Some(MAY_3 - 7 * 86_400));
+        assert_eq!(Bucket::Month.of(MAY_3 + 3_725), Some(MAY_3 - 2 * 86_400));
+
+        assert_eq!(Bucket::Hour.label(MAY_3 + 3_600), "2021-05-03T01:00");
+        assert_eq!(Bucket::Week.label(MAY_3), "2021-W18");
+        assert_eq!(Bucket::Month.label(MAY_3 - 2 * 86_400), "2021-05");
+    }
+
+    #[test]
+    fn buckets_out_of_range() {
+        let min = NaiveDate::MIN
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc()
+            .timestamp();
+
+        for bucket in [Bucket::Hour, Bucket::Day, Bucket::Week, Bucket::Month] {
+            assert_eq!(bucket.of(i64::MAX), None);
+            assert_eq!(bucket.of(i64::MIN), None);
+        }
+
+        assert_eq!(Bucket::Day.of(min), Some(min));
+        assert_eq!(Bucket::Week.of(min + 3_600), None);
+    }
+}
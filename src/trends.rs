@@ -0,0 +1,251 @@
+use hashbrown::HashMap;
+use log::info;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::post::Counts;
+use crate::time::Bucket;
+use crate::{Config, PhraseBuf};
+
+struct Spike {
+    bucket: i64,
+    score: f64,
+    count: u32,
+    expected: f64,
+}
+
+/// how far `count` is above what the phrase's baseline rate predicts for a bucket with
+/// `total` occurrences, in standard deviations of a poisson distribution
+///
+/// the baseline is smoothed by one occurrence, so that new phrases rank by how often
+/// they occur instead of all scoring infinity
+fn z_score(count: u32, total: u64, baseline_count: u64, baseline_total: u64) -> (f64, f64) {
+    let rate = (baseline_count + 1) as f64 / (baseline_total + 1) as f64;
+    let expected = rate * total as f64;
+
+    ((f64::from(count) - expected) / expected.sqrt(), expected)
+}
+
+/// the phrases whose rate in one of the last --trend-window buckets is much higher than in
+/// the buckets before it, highest z-score first
+fn spikes(config: &Config, phrases: Vec<(PhraseBuf, Counts)>) -> Vec<(PhraseBuf, Spike)> {
+    // phrases are compared with how much was said in a bucket, not with absolute counts,
+    // so that busy periods don't look like spikes for every phrase
+    let mut totals = HashMap::<i64, u64>::new();
+
+    for (_, counts) in &phrases {
+        for (&start, &count) in counts
+            .buckets()
+            .into_iter()
+            .flat_map(|buckets| buckets.iter())
+        {
+            *totals.entry(start).or_default() += u64::from(count);
+        }
+    }
+
+    let mut buckets = totals.into_iter().collect::<Vec<_>>();
+    buckets.sort_unstable();
+
+    let window_start = buckets.len().saturating_sub(config.trend_window.get());
+    let mut spikes = Vec::new();
+    let mut counts_per_bucket = Vec::with_capacity(buckets.len());
+
+    for (phrase, counts) in phrases {
+        let phrase_buckets = match counts.buckets() {
+            Some(phrase_buckets) => phrase_buckets,
+            None => continue,
+        };
+
+        counts_per_bucket.clear();
+        counts_per_bucket.extend(
+            buckets
+                .iter()
+                .map(|(start, _)| phrase_buckets.get(start).copied().unwrap_or(0)),
+        );
+
+        let mut best: Option<Spike> = None;
+
+        for i in window_start..buckets.len() {
+            let (start, total) = buckets[i];
+            let count = counts_per_bucket[i];
+
+            if count < config.min_spike_count {
+                continue;
+            }
+
+            let baseline_start = config
+                .trend_baseline
+                .map_or(0, |baseline| i.saturating_sub(baseline.get()));
+
+            if baseline_start == i {
+                continue;
+            }
+
+            let baseline_count = counts_per_bucket[baseline_start..i]
+                .iter()
+                .map(|&count| u64::from(count))
+                .sum();
+            let baseline_total = buckets[baseline_start..i]
+                .iter()
+                .map(|&(_, total)| total)
+                .sum();
+
+            let (score, expected) = z_score(count, total, baseline_count, baseline_total);
+
+            if score >= config.min_z_score && best.as_ref().is_none_or(|best| score > best.score) {
+                best = Some(Spike {
+                    bucket: start,
+                    score,
+                    count,
+                    expected,
+                });
+            }
+        }
+
+        if let Some(spike) = best {
+            spikes.push((phrase, spike));
+        }
+    }
+
+    spikes.sort_unstable_by(|(a_phrase, a), (b_phrase, b)| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a_phrase.cmp(b_phrase))
+    });
+
+    spikes
+}
+
+pub fn write_trends(
+    config: &Config,
+    bucket: Bucket,
+    path: &Path,
+    phrases: Vec<(PhraseBuf, Counts)>,
+) -> io::Result<()> {
+    let spikes = spikes(config, phrases);
+
+    info!("prepare to emit: {} trending phrases", spikes.len());
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .read(false)
+        .open(path)?;
+    let mut file = BufWriter::new(file);
+
+    writeln!(file, "phrase\tperiod\tz_score\tcount\texpected")?;
+
+    for (phrase, spike) in spikes {
+        writeln!(
+            file,
+            "{}\t{}\t{:.2}\t{}\t{:.2}",
+            phrase,
+            bucket.label(spike.bucket),
+            spike.score,
+            spike.count,
+            spike.expected
+        )?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::{Meta, Post};
+    use crate::tests::config;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    /// a phrase that occurs `count` times on each day
+    fn phrase(config: &Config, phrase: &str, days: &[u32]) -> (PhraseBuf, Counts) {
+        let mut counts = Counts::default();
+
+        for (day, &count) in days.iter().enumerate() {
+            let meta = Meta {
+                timestamp: Some(day as i64 * DAY),
+                ..Meta::default()
+            };
+            let post = Post::new(config, &meta).unwrap();
+
+            for _ in 0..count {
+                counts.add(&post);
+            }
+        }
+
+        (phrase.into(), counts)
+    }
+
+    fn trending(args: &[&str], phrases: &[(&str, &[u32])]) -> Vec<(String, i64)> {
+        let config = config(
+            &[
+                &["--buckets", "day", "--timestamp", "created", "--trends"],
+                args,
+            ]
+            .concat(),
+        );
+        let phrases = phrases
+            .iter()
+            .map(|(name, days)| phrase(&config, name, days))
+            .collect();
+
+        spikes(&config, phrases)
+            .into_iter()
+            .map(|(phrase, spike)| (phrase.into(), spike.bucket / DAY))
+            .collect()
+    }
+
+    #[test]
+    fn z_scores() {
+        // 10 of 100 in the baseline predicts 10 of 100 in the bucket
+        let (score, expected) = z_score(10, 100, 9, 99);
+        assert_eq!(expected, 10.0);
+        assert_eq!(score, 0.0);
+
+        let (score, _) = z_score(20, 100, 9, 99);
+        assert!((score - 10.0 / 10f64.sqrt()).abs() < 1e-9);
+
+        // a phrase that is new in the bucket doesn't score infinity
+        assert!(z_score(20, 100, 0, 1000).0.is_finite());
+    }
+
+    #[test]
+    fn spikes_are_found_in_the_window() {
+        let phrases: &[(&str, &[u32])] = &[
+            ("steady", &[100, 100, 100, 100]),
+            ("early", &[1, 40, 1, 1]),
+            ("late", &[1, 1, 1, 40]),
+        ];
+
+        assert_eq!(trending(&[], phrases), [("late".to_owned(), 3)]);
+        assert_eq!(
+            trending(&["--trend-window", "3"], phrases),
+            [("late".to_owned(), 3), ("early".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn spikes_are_compared_with_the_baseline() {
+        // busy on the first day and on the last, which is only a spike next to the day before
+        let phrases: &[(&str, &[u32])] = &[("back", &[40, 1, 40]), ("steady", &[40, 40, 40])];
+
+        assert!(trending(&[], phrases).is_empty());
+        assert_eq!(
+            trending(&["--trend-baseline", "1"], phrases),
+            [("back".to_owned(), 2)]
+        );
+    }
+
+    #[test]
+    fn spikes_need_enough_occurrences() {
+        let phrases: &[(&str, &[u32])] = &[("rare", &[0, 0, 0, 4]), ("steady", &[40, 40, 40, 40])];
+
+        assert!(trending(&[], phrases).is_empty());
+        assert_eq!(
+            trending(&["--min-spike-count", "4"], phrases),
+            [("rare".to_owned(), 3)]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use hashbrown::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+static ANONYMOUS_USER_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// 2^12 registers, a standard error of about 1.6%
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+/// a set of this many users takes about as much memory as a sketch
+const SPARSE_LIMIT: usize = REGISTERS / 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Exact,
+    /// switch to a hyperloglog sketch once a phrase has been used by many users
+    Approximate,
+}
+
+impl Mode {
+    pub const NAMES: &'static [&'static str] = &["exact", "approximate"];
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "exact" => Ok(Mode::Exact),
+            "approximate" => Ok(Mode::Approximate),
+            _ => Err(format!(
+                "unknown mode `{}`, expected one of {}",
+                name,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// the hash of a user id, hashed with fixed keys so that every thread agrees on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct User(u64);
+
+impl User {
+    pub fn named(id: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        (1_u8, id).hash(&mut hasher);
+        User(hasher.finish())
+    }
+
+    /// a user without an id, that is different from every other user
+    pub fn anonymous() -> Self {
+        let mut hasher = DefaultHasher::new();
+        (0_u8, ANONYMOUS_USER_COUNT.fetch_add(1, Relaxed)).hash(&mut hasher);
+        User(hasher.finish())
+    }
+}
+
+/// user ids, one per line
+pub fn read_list(path: &Path) -> io::Result<HashSet<User>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(User::named)
+        .collect())
+}
+
+/// the distinct users of a phrase
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Users {
+    Exact(HashSet<u64>),
+    /// a phrase with few users in approximate mode, becomes a sketch once it has more
+    Sparse(HashSet<u64>),
+    Sketch(Box<[u8]>),
+}
+
+impl Users {
+    pub fn new(mode: Mode) -> Self {
+        match mode {
+            Mode::Exact => Users::Exact(HashSet::new()),
+            Mode::Approximate => Users::Sparse(HashSet::new()),
+        }
+    }
+
+    pub fn insert(&mut self, user: User) {
+        match self {
+            Users::Exact(users) | Users::Sparse(users) => {
+                users.insert(user.0);
+            }
+            Users::Sketch(registers) => add_to_sketch(registers, user.0),
+        }
+
+        self.limit();
+    }
+
+    pub fn merge(&mut self, other: Users) {
+        match (&mut *self, other) {
+            (
+                Users::Exact(users) | Users::Sparse(users),
+                Users::Exact(other) | Users::Sparse(other),
+            ) => users.extend(other),
+            (Users::Sketch(registers), Users::Exact(other) | Users::Sparse(other)) => {
+                for user in other {
+                    add_to_sketch(registers, user);
+                }
+            }
+            (Users::Sketch(registers), Users::Sketch(other)) => {
+                for (register, other) in registers.iter_mut().zip(other.iter()) {
+                    *register = (*register).max(*other);
+                }
+            }
+            (_, other @ Users::Sketch(_)) => {
+                let users = std::mem::replace(self, other);
+                self.merge(users);
+            }
+        }
+
+        self.limit();
+    }
+
+    /// a set of sparse users never grows past the size of a sketch
+    fn limit(&mut self) {
+        let users = match self {
+            Users::Sparse(users) if users.len() > SPARSE_LIMIT => users,
+            _ => return,
+        };
+
+        let mut registers = vec![0; REGISTERS].into_boxed_slice();
+
+        for &user in users.iter() {
+            add_to_sketch(&mut registers, user);
+        }
+
+        *self = Users::Sketch(registers);
+    }
+
+    pub fn count(&self) -> u64 {
+        match self {
+            Users::Exact(users) | Users::Sparse(users) => users.len() as u64,
+            Users::Sketch(registers) => estimate(registers),
+        }
+    }
+}
+
+fn add_to_sketch(registers: &mut [u8], user: u64) {
+    let index = (user >> (64 - PRECISION)) as usize;
+    // the position of the first set bit in the rest of the hash
+    let rank = ((user << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() + 1;
+
+    registers[index] = registers[index].max(rank as u8);
+}
+
+fn estimate(registers: &[u8]) -> u64 {
+    let m = REGISTERS as f64;
+    let alpha = 0.7213 / (1.0 + 1.079 / m);
+
+    let sum: f64 = registers
+        .iter()
+        .map(|&register| 2_f64.powi(-i32::from(register)))
+        .sum();
+    let estimate = alpha * m * m / sum;
+
+    let zeros = registers.iter().filter(|&&register| register == 0).count();
+
+    // linear counting is more accurate while many registers are still empty
+    if estimate <= 2.5 * m && zeros != 0 {
+        (m * (m / zeros as f64).ln()).round() as u64
+    } else {
+        estimate.round() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users(mode: Mode, ids: std::ops::Range<u32>) -> Users {
+        let mut users = Users::new(mode);
+
+        for id in ids {
+            users.insert(User::named(&id.to_string()));
+        }
+
+        users
+    }
+
+    /// a sketch is within 5% (about three standard errors) of the exact count
+    fn assert_close(estimate: u64, exact: u32) {
+        let error = (estimate as f64 - f64::from(exact)).abs() / f64::from(exact);
+        assert!(error < 0.05, "estimated {} for {}", estimate, exact);
+    }
+
+    #[test]
+    fn exact_counts() {
+        let mut a = users(Mode::Exact, 0..5_000);
+        a.insert(User::named("0"));
+        assert_eq!(a.count(), 5_000);
+
+        a.merge(users(Mode::Exact, 4_000..6_000));
+        assert_eq!(a.count(), 6_000);
+    }
+
+    #[test]
+    fn sparse_sets_are_exact() {
+        let users = users(Mode::Approximate, 0..SPARSE_LIMIT as u32);
+        assert!(matches!(users, Users::Sparse(_)));
+        assert_eq!(users.count(), SPARSE_LIMIT as u64);
+    }
+
+    #[test]
+    fn estimate_error_bounds() {
+        for exact in [1_000, 5_000, 20_000, 100_000] {
+            let users = users(Mode::Approximate, 0..exact);
+            assert!(matches!(users, Users::Sketch(_)));
+            assert_close(users.count(), exact);
+        }
+    }
+
+    #[test]
+    fn merged_sketches_count_the_union() {
+        let mut a = users(Mode::Approximate, 0..30_000);
+        a.merge(users(Mode::Approximate, 20_000..50_000));
+        assert_close(a.count(), 50_000);
+
+        let mut sparse = users(Mode::Approximate, 0..100);
+        sparse.merge(users(Mode::Approximate, 100..10_000));
+        assert_close(sparse.count(), 10_000);
+    }
+
+    #[test]
+    fn empty_sketch() {
+        assert_eq!(estimate(&[0; REGISTERS]), 0);
+    }
+}
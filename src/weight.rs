@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::AddAssign;
+use std::str::FromStr;
+
+/// weights are stored in thousandths
+const SCALE: f64 = 1000.0;
+
+/// how the score of a post becomes the weight each of its phrases is counted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    Linear,
+    /// `ln(1 + score)`, so that a few viral posts don't drown out everything else
+    Log,
+}
+
+impl Transform {
+    pub const NAMES: &'static [&'static str] = &["linear", "log"];
+
+    /// posts without a score and negative scores weigh nothing
+    pub fn weight(self, score: Option<f64>) -> Weight {
+        let score = score
+            .filter(|score| score.is_finite())
+            .unwrap_or(0.0)
+            .max(0.0);
+
+        let weight = match self {
+            Transform::Linear => score,
+            Transform::Log => score.ln_1p(),
+        };
+
+        Weight((weight * SCALE).round() as i64)
+    }
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "linear" => Ok(Transform::Linear),
+            "log" => Ok(Transform::Log),
+            _ => Err(format!(
+                "unknown weight `{}`, expected one of {}",
+                name,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// a sum of weights in fixed point, so that totals don't depend on the order maps are merged in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Weight(i64);
+
+impl Weight {
+    /// the weight of `count` unweighted occurrences
+    pub fn count(count: u32) -> Self {
+        Weight(i64::from(count) * SCALE as i64)
+    }
+}
+
+impl AddAssign for Weight {
+    fn add_assign(&mut self, other: Weight) {
+        self.0 = self.0.saturating_add(other.0);
+    }
+}
+
+impl fmt::Display for Weight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0 as f64 / SCALE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transforms() {
+        assert_eq!(Transform::Linear.weight(Some(2.5)).to_string(), "2.5");
+        assert_eq!(Transform::Log.weight(Some(0.0)), Weight::default());
+        assert_eq!(
+            Transform::Log.weight(Some(std::f64::consts::E - 1.0)),
+            Weight::count(1)
+        );
+        // thousandths are rounded
+        assert_eq!(Transform::Log.weight(Some(1.0)).to_string(), "0.693");
+    }
+
+    #[test]
+    fn invalid_scores_weigh_nothing() {
+        for transform in [Transform::Linear, Transform::Log] {
+            for score in [None, Some(-3.0), Some(f64::NAN), Some(f64::INFINITY)] {
+                assert_eq!(transform.weight(score), Weight::default(), "{:?}", score);
+            }
+        }
+    }
+
+    #[test]
+    fn sums_saturate() {
+        let mut weight = Transform::Linear.weight(Some(1e300));
+        weight += Weight::count(1);
+        assert!(weight > Weight::count(u32::MAX));
+    }
+
+    #[test]
+    fn names() {
+        for name in Transform::NAMES {
+            assert!(name.parse::<Transform>().is_ok());
+        }
+
+        assert!("square".parse::<Transform>().is_err());
+    }
+}